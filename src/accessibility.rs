@@ -0,0 +1,469 @@
+// Accessibility audit of a converted book.
+//
+// Runs over the chapter pages listed in `spine.csv` once they are written,
+// looking only at the book content, not the reader around it nor the
+// paragraph anchors and note popovers the converter adds to it. Each
+// image, heading, table, link, inline colour and page is one check. The
+// score averages the share of passed checks of each kind, so a long book
+// doesn't make up for missing alt text with many fine paragraphs. The
+// findings go to `accessibility-report.json` and
+// `accessibility-report.html`.
+
+use scraper::{ElementRef, Html, Selector};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tera::{Context, Tera};
+
+// WCAG AA contrast, and the lower one for large text (headings).
+const MIN_CONTRAST: f64 = 4.5;
+const MIN_CONTRAST_LARGE: f64 = 3.0;
+
+type Rgb = (u8, u8, u8);
+
+const HEADINGS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+const NAMED_COLORS: &[(&str, Rgb)] = &[
+    ("aqua", (0, 255, 255)),
+    ("black", (0, 0, 0)),
+    ("blue", (0, 0, 255)),
+    ("fuchsia", (255, 0, 255)),
+    ("gray", (128, 128, 128)),
+    ("green", (0, 128, 0)),
+    ("grey", (128, 128, 128)),
+    ("lime", (0, 255, 0)),
+    ("maroon", (128, 0, 0)),
+    ("navy", (0, 0, 128)),
+    ("olive", (128, 128, 0)),
+    ("orange", (255, 165, 0)),
+    ("purple", (128, 0, 128)),
+    ("red", (255, 0, 0)),
+    ("silver", (192, 192, 192)),
+    ("teal", (0, 128, 128)),
+    ("white", (255, 255, 255)),
+    ("yellow", (255, 255, 0)),
+];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Issue {
+    /// `image-alt`, `heading-order`, `language`, `table-headers`,
+    /// `contrast` or `link-text`.
+    pub kind: String,
+    pub file: String,
+    pub element: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AccessibilityReport {
+    pub pages: usize,
+    pub checked: usize,
+    /// Number of checks of each kind.
+    pub checks: BTreeMap<String, usize>,
+    /// Share of checks that passed in percent, averaged over the kinds
+    /// checked, 100 when there was nothing to check.
+    pub score: u32,
+    /// Number of issues of each kind.
+    pub counts: BTreeMap<String, usize>,
+    pub issues: Vec<Issue>,
+}
+
+/// The score and issue counts of a book, for the batch report.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AccessibilityScore {
+    pub score: u32,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub issues: BTreeMap<String, usize>,
+}
+
+impl AccessibilityReport {
+    fn check(&mut self, passed: bool, kind: &str, file: &str, element: String, message: String) {
+        self.checked += 1;
+        *self.checks.entry(kind.to_string()).or_default() += 1;
+        if !passed {
+            *self.counts.entry(kind.to_string()).or_default() += 1;
+            self.issues.push(Issue {
+                kind: kind.to_string(),
+                file: file.to_string(),
+                element,
+                message,
+            });
+        }
+    }
+
+    fn compute_score(&mut self) {
+        if self.checks.is_empty() {
+            self.score = 100;
+            return;
+        }
+        let passed: f64 = self
+            .checks
+            .iter()
+            .map(|(kind, checked)| {
+                let failed = self.counts.get(kind).copied().unwrap_or_default();
+                (checked - failed) as f64 / *checked as f64
+            })
+            .sum();
+        self.score = (passed * 100.0 / self.checks.len() as f64).floor() as u32;
+    }
+
+    pub fn summary(&self) -> AccessibilityScore {
+        AccessibilityScore {
+            score: self.score,
+            issues: self.counts.clone(),
+        }
+    }
+}
+
+// The tag with whatever attribute tells it apart, to find it in the page.
+fn describe(element: &ElementRef) -> String {
+    let value = element.value();
+    let name = value.name();
+    for attribute in ["id", "src", "href", "class"].iter() {
+        if let Some(v) = value.attr(attribute) {
+            return format!("<{} {}=\"{}\">", name, attribute, v);
+        }
+    }
+    format!("<{}>", name)
+}
+
+// An opaque or translucent colour; fully transparent ones count as unset,
+// the background behind them shows.
+fn parse_color(value: &str) -> Option<Rgb> {
+    let value = value.trim().trim_end_matches("!important").trim().to_lowercase();
+    if let Some(hex) = value.strip_prefix('#') {
+        let digits: Vec<u8> = hex
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()?;
+        return match digits.len() {
+            4 if digits[3] == 0 => None,
+            8 if digits[6] == 0 && digits[7] == 0 => None,
+            3 | 4 => Some((digits[0] * 17, digits[1] * 17, digits[2] * 17)),
+            6 | 8 => Some((
+                digits[0] * 16 + digits[1],
+                digits[2] * 16 + digits[3],
+                digits[4] * 16 + digits[5],
+            )),
+            _ => None,
+        };
+    }
+    if value.starts_with("rgb") {
+        let open = value.find('(')? + 1;
+        let inner = &value[open..open + value[open..].find(')')?];
+        let parts: Vec<&str> = inner
+            .split([',', ' ', '/'])
+            .filter(|s| !s.is_empty())
+            .collect();
+        if parts.len() < 3 {
+            return None;
+        }
+        if let Some(alpha) = parts.get(3) {
+            let alpha = match alpha.strip_suffix('%') {
+                Some(percent) => percent.parse::<f64>().ok()? / 100.0,
+                None => alpha.parse::<f64>().ok()?,
+            };
+            if alpha <= 0.0 {
+                return None;
+            }
+        }
+        let channels: Vec<u8> = parts[..3]
+            .iter()
+            .map(|s| match s.strip_suffix('%') {
+                Some(percent) => percent.parse::<f64>().ok().map(|p| p * 255.0 / 100.0),
+                None => s.parse::<f64>().ok(),
+            })
+            .map(|c| c.map(|c| c.clamp(0.0, 255.0).round() as u8))
+            .collect::<Option<_>>()?;
+        return Some((channels[0], channels[1], channels[2]));
+    }
+    NAMED_COLORS
+        .iter()
+        .find(|(name, _)| *name == value)
+        .map(|(_, rgb)| *rgb)
+}
+
+fn luminance((r, g, b): Rgb) -> f64 {
+    let channel = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * channel(r) + 0.7152 * channel(g) + 0.0722 * channel(b)
+}
+
+fn contrast(a: Rgb, b: Rgb) -> f64 {
+    let (a, b) = (luminance(a), luminance(b));
+    (a.max(b) + 0.05) / (a.min(b) + 0.05)
+}
+
+fn hex((r, g, b): Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+// The text and background colours set in an inline `style`, if any.
+fn inline_colors(element: &ElementRef) -> (Option<Rgb>, Option<Rgb>) {
+    let mut color = None;
+    let mut background = None;
+    let style = element.value().attr("style").unwrap_or_default();
+    for declaration in style.split(';') {
+        let mut parts = declaration.splitn(2, ':');
+        let property = parts.next().unwrap_or_default().trim().to_lowercase();
+        let value = parts.next().unwrap_or_default();
+        match property.as_str() {
+            "color" => color = parse_color(value),
+            "background-color" => background = parse_color(value),
+            "background" => {
+                background = parse_color(value)
+                    .or_else(|| value.split_whitespace().filter_map(parse_color).next())
+            }
+            _ => {}
+        }
+    }
+    (color, background)
+}
+
+// The colours the text of `element` ends up in, from its own and its
+// ancestors' inline styles, black on white for the one not set. `None`
+// when no inline style sets either.
+fn effective_colors(element: &ElementRef) -> Option<(Rgb, Rgb)> {
+    let mut color = None;
+    let mut background = None;
+    let elements = Some(*element)
+        .into_iter()
+        .chain(element.ancestors().filter_map(ElementRef::wrap));
+    for e in elements {
+        let (c, b) = inline_colors(&e);
+        color = color.or(c);
+        background = background.or(b);
+    }
+    if color.is_none() && background.is_none() {
+        return None;
+    }
+    Some((
+        color.unwrap_or((0, 0, 0)),
+        background.unwrap_or((255, 255, 255)),
+    ))
+}
+
+fn has_text(element: &ElementRef) -> bool {
+    element.text().any(|t| !t.trim().is_empty())
+}
+
+fn has_own_text(element: &ElementRef) -> bool {
+    element
+        .children()
+        .filter_map(|child| child.value().as_text())
+        .any(|t| !t.trim().is_empty())
+}
+
+// The paragraph anchors and note popover copies the converter adds, which
+// would count every paragraph and every note twice.
+fn added_by_converter(element: &ElementRef) -> bool {
+    let has_class = |e: &ElementRef, class: &str| e.value().classes().any(|c| c == class);
+    has_class(element, "para-anchor")
+        || Some(*element)
+            .into_iter()
+            .chain(element.ancestors().filter_map(ElementRef::wrap))
+            .any(|e| has_class(&e, "note-popovers"))
+}
+
+fn audit_page(html: &str, file: &str, report: &mut AccessibilityReport) {
+    let document = Html::parse_document(html);
+    let content = Selector::parse(".book-content").unwrap();
+    let all = Selector::parse("*").unwrap();
+    let headers = Selector::parse("th").unwrap();
+    let images_with_alt = Selector::parse("img[alt]").unwrap();
+
+    let root = document.root_element();
+    let lang = root.value().attr("lang").unwrap_or_default();
+    report.check(
+        !lang.trim().is_empty(),
+        "language",
+        file,
+        "<html>".to_string(),
+        "the page doesn't declare its language".to_string(),
+    );
+
+    let mut level = None;
+    for section in document.select(&content) {
+        for element in section.select(&all) {
+            if added_by_converter(&element) {
+                continue;
+            }
+            let value = element.value();
+            let name = value.name();
+            match name {
+                "img" => report.check(
+                    value.attr("alt").is_some(),
+                    "image-alt",
+                    file,
+                    describe(&element),
+                    "image without alt text".to_string(),
+                ),
+                _ if HEADINGS.contains(&name) => {
+                    let this = name[1..].parse::<u32>().unwrap_or(1);
+                    if let Some(previous) = level {
+                        report.check(
+                            this <= previous + 1,
+                            "heading-order",
+                            file,
+                            describe(&element),
+                            format!("<{}> follows <h{}>, skipping a level", name, previous),
+                        );
+                    }
+                    level = Some(this);
+                }
+                "table" => {
+                    let role = value.attr("role").unwrap_or_default();
+                    if role != "presentation" && role != "none" {
+                        report.check(
+                            element.select(&headers).next().is_some(),
+                            "table-headers",
+                            file,
+                            describe(&element),
+                            "table without header cells".to_string(),
+                        );
+                    }
+                }
+                "a" if value.attr("href").is_some() => {
+                    let labelled = ["aria-label", "aria-labelledby", "title"]
+                        .iter()
+                        .any(|a| value.attr(a).is_some_and(|v| !v.trim().is_empty()));
+                    let image_text = element
+                        .select(&images_with_alt)
+                        .any(|img| !img.value().attr("alt").unwrap_or_default().trim().is_empty());
+                    report.check(
+                        labelled || image_text || has_text(&element),
+                        "link-text",
+                        file,
+                        describe(&element),
+                        "link without text".to_string(),
+                    );
+                }
+                _ => {}
+            }
+
+            // links take their colour from the reader's stylesheet, not
+            // from inline styles around them
+            if !has_own_text(&element) || (name == "a" && inline_colors(&element).0.is_none()) {
+                continue;
+            }
+            if let Some((color, background)) = effective_colors(&element) {
+                let ratio = contrast(color, background);
+                let needed = if HEADINGS.contains(&name) {
+                    MIN_CONTRAST_LARGE
+                } else {
+                    MIN_CONTRAST
+                };
+                report.check(
+                    ratio >= needed,
+                    "contrast",
+                    file,
+                    describe(&element),
+                    format!(
+                        "contrast of {:.1}:1 between {} and {}, needs {}:1",
+                        ratio,
+                        hex(color),
+                        hex(background),
+                        needed
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Audits the chapter pages in `output_root`.
+pub fn audit(output_root: &Path) -> AccessibilityReport {
+    let mut report = AccessibilityReport::default();
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(output_root.join("spine.csv"))
+        .expect("Can't read spine.csv");
+    let files: Vec<String> = reader
+        .records()
+        .filter_map(|r| r.ok())
+        .filter_map(|r| r.get(1).map(|s| s.to_string()))
+        .collect();
+    for file in files {
+        if let Ok(html) = fs::read_to_string(output_root.join(&file)) {
+            report.pages += 1;
+            audit_page(&html, &file, &mut report);
+        }
+    }
+    report.compute_score();
+    report
+}
+
+/// Writes `accessibility-report.json` and, rendered with the book's `ctx`,
+/// `accessibility-report.html`.
+pub fn write_report(
+    output_root: &Path,
+    tera: &Tera,
+    mut ctx: Context,
+    report: &AccessibilityReport,
+) {
+    let j = serde_json::to_string_pretty(report).expect("Can't serialize accessibility report");
+    fs::write(output_root.join("accessibility-report.json"), &j)
+        .expect("Can't write accessibility-report.json");
+    ctx.insert("report", report);
+    let kinds: Vec<_> = report
+        .checks
+        .iter()
+        .map(|(kind, checked)| {
+            let issues = report.counts.get(kind).copied().unwrap_or_default();
+            json!({ "kind": kind, "checked": checked, "issues": issues })
+        })
+        .collect();
+    ctx.insert("kinds", &kinds);
+    let rendered = tera
+        .render("accessibility-report.html", &ctx)
+        .expect("Failed to render accessibility report");
+    fs::write(output_root.join("accessibility-report.html"), rendered)
+        .expect("Can't write accessibility-report.html");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{contrast, parse_color};
+
+    #[test]
+    fn parses_hex_rgb_and_named_colors() {
+        assert_eq!(parse_color("#fff"), Some((255, 255, 255)));
+        assert_eq!(parse_color("#1A2b3C"), Some((0x1a, 0x2b, 0x3c)));
+        assert_eq!(parse_color("#102030ff"), Some((0x10, 0x20, 0x30)));
+        assert_eq!(parse_color("rgb(255, 0, 10)"), Some((255, 0, 10)));
+        assert_eq!(parse_color("rgb(100% 0% 50%)"), Some((255, 0, 128)));
+        assert_eq!(parse_color("rgba(1, 2, 3, 0.5)"), Some((1, 2, 3)));
+        assert_eq!(parse_color(" Black !important"), Some((0, 0, 0)));
+        assert_eq!(parse_color("#ggg"), None);
+        assert_eq!(parse_color("inherit"), None);
+        assert_eq!(parse_color("rgb)("), None);
+        assert_eq!(parse_color("rgb(1, 2"), None);
+    }
+
+    #[test]
+    fn treats_transparent_colors_as_unset() {
+        assert_eq!(parse_color("transparent"), None);
+        assert_eq!(parse_color("#0000"), None);
+        assert_eq!(parse_color("#ffffff00"), None);
+        assert_eq!(parse_color("rgba(255, 255, 255, 0)"), None);
+        assert_eq!(parse_color("rgb(0 0 0 / 0%)"), None);
+    }
+
+    #[test]
+    fn computes_contrast_ratios() {
+        let black = (0, 0, 0);
+        let white = (255, 255, 255);
+        assert!((contrast(black, white) - 21.0).abs() < 0.01);
+        assert!((contrast(white, black) - 21.0).abs() < 0.01);
+        assert!((contrast(white, white) - 1.0).abs() < 0.01);
+        // #777 on white is just under the 4.5:1 of WCAG AA
+        let gray = (0x77, 0x77, 0x77);
+        assert!((contrast(gray, white) - 4.48).abs() < 0.01);
+    }
+}
@@ -0,0 +1,118 @@
+// Precompressed copies of the text files of a book.
+//
+// Static servers (nginx `gzip_static` and `brotli_static`, for one) send
+// `page.html.gz` or `page.html.br` when they exist instead of compressing
+// on every request. A copy is only written when it saves enough to be
+// worth the server's trouble; small files often don't. The library index
+// and OPDS feeds of a batch get the same treatment.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use fs_extra::dir::get_dir_content;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+
+const EXTENSIONS: &[&str] = &["html", "css", "js", "json", "svg", "webmanifest", "xml"];
+
+// Copies that aren't at least this much smaller are not written.
+const MIN_SAVING: f64 = 0.1;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompressedFile {
+    pub path: String,
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gzip: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brotli: Option<usize>,
+}
+
+/// The precompressed files of a book or of the library, for the batch
+/// report.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PrecompressReport {
+    /// Files with at least one compressed copy.
+    pub files: Vec<CompressedFile>,
+    /// Files where neither compression helped.
+    pub skipped: usize,
+    pub gzip_bytes: usize,
+    pub brotli_bytes: usize,
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::best());
+    encoder.write_all(data).expect("Can't gzip");
+    encoder.finish().expect("Can't gzip")
+}
+
+fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut compressed = vec![];
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: 11,
+        size_hint: data.len(),
+        ..Default::default()
+    };
+    brotli::BrotliCompress(&mut Cursor::new(data), &mut compressed, &params)
+        .expect("Can't compress with brotli");
+    compressed
+}
+
+// Writes `compressed` next to `file` with `extension` added, if it helps.
+fn write_copy(file: &Path, extension: &str, size: usize, compressed: Vec<u8>) -> Option<usize> {
+    if compressed.len() as f64 > size as f64 * (1.0 - MIN_SAVING) {
+        return None;
+    }
+    let mut name = file.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    fs::write(&name, &compressed)
+        .unwrap_or_else(|e| panic!("Can't write {}: {}", Path::new(&name).display(), e));
+    Some(compressed.len())
+}
+
+/// Writes `.gz` and `.br` copies of every HTML, CSS, JS, JSON, SVG, XML
+/// and webmanifest file in `output_root`.
+pub fn precompress(output_root: &Path) -> PrecompressReport {
+    let content = get_dir_content(output_root).expect("Can't read output folder");
+    let files: Vec<PathBuf> = content.files.iter().map(PathBuf::from).collect();
+    precompress_files(output_root, &files)
+}
+
+/// `precompress` for just `files`, for folders shared with other output,
+/// like a library index with the books below it.
+pub fn precompress_files(output_root: &Path, files: &[PathBuf]) -> PrecompressReport {
+    let mut report = PrecompressReport::default();
+    let mut files = files.to_vec();
+    files.sort();
+    for file in files.iter() {
+        let file = file.as_path();
+        let ext = file.extension().and_then(OsStr::to_str).unwrap_or_default();
+        if !EXTENSIONS.contains(&ext) {
+            continue;
+        }
+        let data =
+            fs::read(file).unwrap_or_else(|e| panic!("Can't read {}: {}", file.display(), e));
+        let gzip = write_copy(file, "gz", data.len(), gzip(&data));
+        let brotli = write_copy(file, "br", data.len(), brotli(&data));
+        if gzip.is_none() && brotli.is_none() {
+            trace!("not compressing {}", file.display());
+            report.skipped += 1;
+            continue;
+        }
+        report.gzip_bytes += gzip.unwrap_or_default();
+        report.brotli_bytes += brotli.unwrap_or_default();
+        report.files.push(CompressedFile {
+            path: file
+                .strip_prefix(output_root)
+                .unwrap_or(file)
+                .to_string_lossy()
+                .replace('\\', "/"),
+            size: data.len(),
+            gzip,
+            brotli,
+        });
+    }
+    report
+}
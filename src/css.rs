@@ -0,0 +1,530 @@
+// Stylesheet processing for book CSS.
+//
+// Resources are flattened into `images/` and `resources/` in the output,
+// so every `url()` and `@import` in a book stylesheet has to be pointed at
+// the new location of what it references.
+
+/// Resolves `href` against the folder of `base`, both archive paths.
+pub fn resolve_path(base: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Decodes `%xx` escapes in an href, leaving malformed ones as they are.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // from_str_radix would take a sign as in `%+1`, so check the digits
+        let escaped = bytes.get(i + 1..i + 3).filter(|hex| {
+            bytes[i] == b'%' && hex.iter().all(|b| b.is_ascii_hexdigit())
+        });
+        if let Some(hex) = escaped {
+            let hex = ::std::str::from_utf8(hex).unwrap_or_default();
+            out.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The relative link from a file at `from` to `to`, both relative to the
+/// output folder.
+pub fn relative_path(from: &str, to: &str) -> String {
+    let mut from_dirs: Vec<&str> = from.split('/').collect();
+    from_dirs.pop();
+    let to_parts: Vec<&str> = to.split('/').collect();
+    let common = from_dirs
+        .iter()
+        .zip(to_parts.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut parts = vec![".."; from_dirs.len() - common];
+    parts.extend(to_parts[common..].iter());
+    parts.join("/")
+}
+
+// References that point outside the book or at nothing that gets copied.
+fn is_local(reference: &str) -> bool {
+    !(reference.is_empty()
+        || reference.starts_with('#')
+        || reference.starts_with('/')
+        || reference.contains(':'))
+}
+
+fn split_reference(reference: &str) -> (&str, &str) {
+    match reference.find(['?', '#']) {
+        Some(i) => (&reference[..i], &reference[i..]),
+        None => (reference, ""),
+    }
+}
+
+// Calls `locate` for a local reference, keeping any `?query` or
+// `#fragment` (SVG fonts use those).
+fn relocate<F: FnMut(&str) -> Option<String>>(reference: &str, locate: &mut F) -> Option<String> {
+    if !is_local(reference) {
+        return None;
+    }
+    let (path, suffix) = split_reference(reference);
+    locate(path).map(|new_path| format!("{}{}", new_path, suffix))
+}
+
+/// Rewrites every `url()` and `@import` in `css` with what `locate`
+/// returns for it. `locate` gets the reference without quotes, query or
+/// fragment; returning `None` leaves the reference alone.
+pub fn rewrite_urls<F: FnMut(&str) -> Option<String>>(css: &str, mut locate: F) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+
+    loop {
+        let url = rest.find("url(");
+        let import = rest.find("@import");
+        let start = match (url, import) {
+            (Some(u), Some(i)) => u.min(i),
+            (Some(u), None) => u,
+            (None, Some(i)) => i,
+            (None, None) => break,
+        };
+
+        if Some(start) == url {
+            let inner_start = start + "url(".len();
+            let inner_end = match rest[inner_start..].find(')') {
+                Some(end) => inner_start + end,
+                None => break,
+            };
+            let inner = rest[inner_start..inner_end].trim();
+            let reference = inner.trim_matches(|c| c == '"' || c == '\'');
+            result.push_str(&rest[..inner_start]);
+            match relocate(reference, &mut locate) {
+                Some(new_reference) => result.push_str(&format!("\"{}\"", new_reference)),
+                None => result.push_str(&rest[inner_start..inner_end]),
+            }
+            result.push(')');
+            rest = &rest[inner_end + 1..];
+        } else {
+            // only `@import "x.css"` needs handling here, `@import url(...)`
+            // is picked up as a url on the next round
+            let after = start + "@import".len();
+            let trimmed = rest[after..].trim_start();
+            let quote = trimmed.chars().next().filter(|c| *c == '"' || *c == '\'');
+            let quote = match quote {
+                Some(quote) => quote,
+                None => {
+                    result.push_str(&rest[..after]);
+                    rest = &rest[after..];
+                    continue;
+                }
+            };
+            let inner_start = rest.len() - trimmed.len() + 1;
+            let inner_end = match rest[inner_start..].find(quote) {
+                Some(end) => inner_start + end,
+                None => break,
+            };
+            result.push_str(&rest[..inner_start]);
+            let reference = &rest[inner_start..inner_end];
+            match relocate(reference, &mut locate) {
+                Some(new_reference) => result.push_str(&new_reference),
+                None => result.push_str(reference),
+            }
+            rest = &rest[inner_end..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+// Index of the first of `wanted` in `css` that is not inside a comment,
+// string, or parentheses.
+fn find_delimiter(css: &str, wanted: &[u8]) -> Option<usize> {
+    let bytes = css.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = css[i + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |end| i + 2 + end + 1);
+            }
+            quote @ b'"' | quote @ b'\'' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            b'(' | b'[' => depth += 1,
+            b')' | b']' if depth > 0 => depth -= 1,
+            c if depth == 0 && wanted.contains(&c) => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Index of the `}` closing the block opened at `open`, or the end of `css`.
+fn closing_brace(css: &str, open: usize) -> usize {
+    let mut depth = 0;
+    let mut i = open;
+    while let Some(found) = find_delimiter(&css[i..], b"{}") {
+        i += found;
+        if css.as_bytes()[i] == b'{' {
+            depth += 1;
+        } else {
+            depth -= 1;
+            if depth == 0 {
+                return i;
+            }
+        }
+        i += 1;
+    }
+    css.len()
+}
+
+// Splits the whitespace and comments in front of a prelude from it.
+fn split_leading_trivia(prelude: &str) -> (&str, &str) {
+    let mut rest = prelude;
+    loop {
+        let trimmed = rest.trim_start();
+        if trimmed.starts_with("/*") {
+            rest = match trimmed.find("*/") {
+                Some(end) => &trimmed[end + 2..],
+                None => "",
+            };
+        } else {
+            rest = trimmed;
+            break;
+        }
+    }
+    prelude.split_at(prelude.len() - rest.len())
+}
+
+fn scope_selector(selector: &str, scope: &str) -> String {
+    if let Some(after) = selector.strip_prefix(scope) {
+        // already scoped, unless it is a longer name such as `.book-content-foo`
+        let next = after.chars().next();
+        if next.is_none_or(|c| c.is_whitespace() || ".#:[>+~".contains(c)) {
+            return selector.to_string();
+        }
+    }
+    let first_end = selector
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '+' || c == '~')
+        .unwrap_or(selector.len());
+    let (first, tail) = selector.split_at(first_end);
+    let name_end = first.find(['.', '#', '[', ':']).unwrap_or(first.len());
+    let name = first[..name_end].to_lowercase();
+    let is_root = first.starts_with(":root");
+    if name != "html" && name != "body" && !is_root {
+        return format!("{} {}", scope, selector);
+    }
+
+    // `html body p` and `html > body` are about the same element as `body`
+    let after = tail.trim_start().trim_start_matches('>').trim_start();
+    if name != "body" && after.len() >= 4 && after[..4].eq_ignore_ascii_case("body") {
+        let next = after[4..].chars().next();
+        if next.is_none_or(|c| !c.is_alphanumeric() && c != '-' && c != '_') {
+            return scope_selector(after, scope);
+        }
+    }
+    let qualifiers = if is_root {
+        &first[":root".len()..]
+    } else {
+        &first[name_end..]
+    };
+    format!("{}{}{}", scope, qualifiers, tail)
+}
+
+fn scope_selector_list(list: &str, scope: &str) -> String {
+    let mut scoped = vec![];
+    let mut rest = list;
+    loop {
+        let end = find_delimiter(rest, b",").unwrap_or(rest.len());
+        let selector = &rest[..end];
+        let trimmed = selector.trim();
+        let leading = &selector[..selector.len() - selector.trim_start().len()];
+        let trailing = &selector[selector.trim_end().len()..];
+        scoped.push(format!(
+            "{}{}{}",
+            leading,
+            scope_selector(trimmed, scope),
+            trailing
+        ));
+        if end == rest.len() {
+            break;
+        }
+        rest = &rest[end + 1..];
+    }
+    scoped.join(",")
+}
+
+// At-rules whose blocks hold more rules, as opposed to declarations,
+// keyframes or font descriptors.
+const GROUPING_RULES: &[&str] = &["media", "supports", "container", "layer", "document"];
+
+fn is_grouping_rule(prelude: &str) -> bool {
+    let name = match prelude.strip_prefix('@') {
+        Some(at_rule) => at_rule
+            .split(|c: char| !c.is_alphanumeric() && c != '-')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase(),
+        None => return false,
+    };
+    GROUPING_RULES.contains(&name.trim_start_matches("-moz-"))
+}
+
+/// Prefixes every selector in `css` with `scope`, so a book's stylesheet
+/// only applies inside its content. Rules for `html`, `body` and `:root`
+/// are moved onto the scope element itself.
+pub fn scope_rules(css: &str, scope: &str) -> String {
+    let mut result = String::with_capacity(css.len() + css.len() / 4);
+    let mut rest = css;
+    while let Some(i) = find_delimiter(rest, b"{;}") {
+        if rest.as_bytes()[i] != b'{' {
+            result.push_str(&rest[..=i]);
+            rest = &rest[i + 1..];
+            continue;
+        }
+        let end = closing_brace(rest, i);
+        let (leading, prelude) = split_leading_trivia(&rest[..i]);
+        let block = &rest[i + 1..end];
+        result.push_str(leading);
+        if prelude.starts_with('@') {
+            result.push_str(prelude);
+            result.push('{');
+            if is_grouping_rule(prelude) {
+                result.push_str(&scope_rules(block, scope));
+            } else {
+                result.push_str(block);
+            }
+        } else {
+            result.push_str(&scope_selector_list(prelude, scope));
+            result.push('{');
+            result.push_str(block);
+        }
+        if end < rest.len() {
+            result.push('}');
+            rest = &rest[end + 1..];
+        } else {
+            rest = "";
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// `css` without its comments.
+pub fn strip_comments(css: &str) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        result.push_str(&rest[..start]);
+        rest = match rest[start..].find("*/") {
+            Some(end) => &rest[start + end + 2..],
+            None => "",
+        };
+    }
+    result.push_str(rest);
+    result
+}
+
+fn collect_rules(css: &str, rules: &mut Vec<(String, String)>) {
+    let mut rest = css;
+    while let Some(i) = find_delimiter(rest, b"{;}") {
+        if rest.as_bytes()[i] != b'{' {
+            rest = &rest[i + 1..];
+            continue;
+        }
+        let end = closing_brace(rest, i);
+        let prelude = rest[..i].trim();
+        let block = &rest[i + 1..end];
+        if is_grouping_rule(prelude) {
+            collect_rules(block, rules);
+        } else {
+            rules.push((prelude.to_string(), block.to_string()));
+        }
+        rest = if end < rest.len() {
+            &rest[end + 1..]
+        } else {
+            ""
+        };
+    }
+}
+
+/// The rules in `css` as `(prelude, block)` pairs, without comments. Rules
+/// inside `@media` and the like are listed as if they were top level.
+pub fn rules(css: &str) -> Vec<(String, String)> {
+    let mut rules = vec![];
+    collect_rules(&strip_comments(css), &mut rules);
+    rules
+}
+
+/// The declarations in a rule block or `style` attribute, as lowercase
+/// property names and values without `!important`.
+pub fn declarations(block: &str) -> Vec<(String, String)> {
+    let mut declarations = vec![];
+    let mut rest = block;
+    loop {
+        let end = find_delimiter(rest, b";").unwrap_or(rest.len());
+        let declaration = &rest[..end];
+        if let Some(colon) = declaration.find(':') {
+            let value = declaration[colon + 1..].trim();
+            let value = value.trim_end_matches("!important").trim_end();
+            declarations.push((
+                declaration[..colon].trim().to_lowercase(),
+                value.to_string(),
+            ));
+        }
+        if end == rest.len() {
+            break;
+        }
+        rest = &rest[end + 1..];
+    }
+    declarations
+}
+
+/// Makes the `format()` hint that follows each `.woff2` url say so, for
+/// fonts that were converted from OpenType.
+pub fn set_woff2_formats(css: &str) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(i) = rest.find(".woff2") {
+        let close = match rest[i..].find(')') {
+            Some(close) => i + close + 1,
+            None => break,
+        };
+        result.push_str(&rest[..close]);
+        rest = &rest[close..];
+        let hint = rest.trim_start();
+        if hint.starts_with("format(") {
+            if let Some(end) = hint.find(')') {
+                result.push_str(&rest[..rest.len() - hint.len()]);
+                result.push_str("format(\"woff2\")");
+                rest = &hint[end + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percent_decode, rewrite_urls, scope_rules, scope_selector};
+
+    const SCOPE: &str = ".book-content";
+
+    #[test]
+    fn prefixes_plain_selectors() {
+        assert_eq!(scope_selector("p", SCOPE), ".book-content p");
+        assert_eq!(scope_selector("h1 > em", SCOPE), ".book-content h1 > em");
+        assert_eq!(scope_selector(".note", SCOPE), ".book-content .note");
+    }
+
+    #[test]
+    fn moves_root_selectors_onto_the_scope() {
+        assert_eq!(scope_selector("body", SCOPE), ".book-content");
+        assert_eq!(scope_selector("html", SCOPE), ".book-content");
+        assert_eq!(scope_selector(":root", SCOPE), ".book-content");
+        assert_eq!(scope_selector("body.dark p", SCOPE), ".book-content.dark p");
+        assert_eq!(scope_selector("html > body p", SCOPE), ".book-content p");
+        assert_eq!(scope_selector("html bodyguard", SCOPE), ".book-content bodyguard");
+    }
+
+    #[test]
+    fn leaves_scoped_selectors_alone() {
+        assert_eq!(scope_selector(".book-content", SCOPE), ".book-content");
+        assert_eq!(scope_selector(".book-content p", SCOPE), ".book-content p");
+        assert_eq!(scope_selector(".book-content.x", SCOPE), ".book-content.x");
+        assert_eq!(scope_selector(".book-content>p", SCOPE), ".book-content>p");
+    }
+
+    #[test]
+    fn prefixes_selectors_that_only_start_like_the_scope() {
+        assert_eq!(
+            scope_selector(".book-content-foo", SCOPE),
+            ".book-content .book-content-foo"
+        );
+        assert_eq!(
+            scope_selector(".book-contents p", SCOPE),
+            ".book-content .book-contents p"
+        );
+    }
+
+    #[test]
+    fn scopes_selector_lists_and_grouping_rules() {
+        let css = "h1, h2 { x: y }\n@media print { p{a:b} }\n@font-face { src: url(f) }";
+        assert_eq!(
+            scope_rules(css, SCOPE),
+            concat!(
+                ".book-content h1, .book-content h2 { x: y }\n",
+                "@media print { .book-content p{a:b} }\n",
+                "@font-face { src: url(f) }"
+            )
+        );
+    }
+
+    #[test]
+    fn rewrites_urls_and_imports() {
+        let css = concat!(
+            "@import \"base.css\";\n",
+            "@import url('print.css') print;\n",
+            "a { background: url( ../img/a.png ) }\n",
+            "@font-face { src: url(\"f.svg#font\") }"
+        );
+        let rewritten = rewrite_urls(css, |path| Some(format!("new/{}", path)));
+        assert_eq!(
+            rewritten,
+            concat!(
+                "@import \"new/base.css\";\n",
+                "@import url(\"new/print.css\") print;\n",
+                "a { background: url(\"new/../img/a.png\") }\n",
+                "@font-face { src: url(\"new/f.svg#font\") }"
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_remote_and_unknown_urls_alone() {
+        let css = "a { b: url(data:x); c: url(http://x/y); d: url(#f); e: url(missing.png) }";
+        let rewritten = rewrite_urls(css, |path| {
+            if path == "missing.png" {
+                None
+            } else {
+                Some("found".to_string())
+            }
+        });
+        assert_eq!(rewritten, css);
+    }
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("My%20Font.otf"), "My Font.otf");
+        assert_eq!(percent_decode("caf%C3%A9%2fx"), "café/x");
+        assert_eq!(percent_decode("plain+text"), "plain+text");
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("a%2"), "a%2");
+        assert_eq!(percent_decode("%zz%+1%-1"), "%zz%+1%-1");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+}
@@ -0,0 +1,228 @@
+// Fixed-layout (pre-paginated) pages.
+//
+// Comics and picture books set `rendition:layout` to `pre-paginated`, for
+// the whole book in the OPF metadata or for single spine items in their
+// `properties`. Such a page is drawn at the size its `<meta name="viewport">`
+// declares and rendered with `fixed.html`, which scales it to fit the
+// window. Pages are paired into spreads by their `page-spread-left` and
+// `page-spread-right` properties; unmarked pages alternate sides starting
+// from the first one, which stands alone like a book's first recto.
+
+use scraper::{Html, Selector};
+use std::collections::HashMap;
+
+use super::metadata::{Package, Rendition};
+
+/// Used when a page declares no size at all.
+const DEFAULT_VIEWPORT: (u32, u32) = (1200, 1600);
+
+/// Where a fixed-layout page sits and what it is shown with.
+#[derive(Serialize, Clone, Default)]
+pub struct FixedPage {
+    pub width: u32,
+    pub height: u32,
+    /// `left`, `right` or `center`.
+    pub side: String,
+    /// Output file of the other page of the spread, if there is one.
+    pub partner: String,
+    /// The pages before and after the whole spread, for navigating by
+    /// spread when the spread view is on.
+    pub spread_previous: String,
+    pub spread_next: String,
+}
+
+fn has_property(properties: &[String], wanted: &[&str]) -> bool {
+    properties.iter().any(|p| wanted.contains(&p.as_str()))
+}
+
+/// The fixed-layout pages of a book keyed by manifest id, with their spread
+/// sides and partners. `filenames` has the output file of each manifest id.
+pub fn fixed_pages(
+    package: &Package,
+    rendition: &Rendition,
+    filenames: &HashMap<String, String>,
+) -> HashMap<String, FixedPage> {
+    let book_fixed = rendition.layout == "pre-paginated";
+    // the side a spread starts on, and the one it ends on
+    let (first, second) = if rendition.direction == "rtl" {
+        ("right", "left")
+    } else {
+        ("left", "right")
+    };
+
+    let mut pages = HashMap::new();
+    let mut expected = second;
+    for item in package.spine.iter() {
+        let properties = &item.properties;
+        let fixed = has_property(properties, &["rendition:layout-pre-paginated"])
+            || (book_fixed && !has_property(properties, &["rendition:layout-reflowable"]));
+        if !fixed {
+            expected = second;
+            continue;
+        }
+        let side = if has_property(properties, &["page-spread-left"]) {
+            "left"
+        } else if has_property(properties, &["page-spread-right"]) {
+            "right"
+        } else if has_property(
+            properties,
+            &["rendition:page-spread-center", "page-spread-center"],
+        ) {
+            "center"
+        } else {
+            expected
+        };
+        expected = if side == first { second } else { first };
+        pages.insert(
+            item.idref.clone(),
+            FixedPage {
+                side: side.to_string(),
+                ..Default::default()
+            },
+        );
+    }
+
+    if rendition.spread == "none" {
+        return pages;
+    }
+    let filename = |index: Option<usize>| {
+        index
+            .and_then(|i| package.spine.get(i))
+            .and_then(|item| filenames.get(&item.idref))
+            .cloned()
+            .unwrap_or_default()
+    };
+    for (i, pair) in package.spine.windows(2).enumerate() {
+        let (start, end) = (&pair[0].idref, &pair[1].idref);
+        let paired = match (pages.get(start), pages.get(end)) {
+            (Some(a), Some(b)) => a.side == first && b.side == second,
+            _ => false,
+        };
+        if !paired {
+            continue;
+        }
+        let previous = filename(i.checked_sub(1));
+        let next = filename(Some(i + 2));
+        for (page, partner) in [(start, end), (end, start)].iter() {
+            let partner = filenames.get(*partner).cloned().unwrap_or_default();
+            if let Some(page) = pages.get_mut(*page) {
+                page.partner = partner;
+                page.spread_previous = previous.clone();
+                page.spread_next = next.clone();
+            }
+        }
+    }
+    pages
+}
+
+// `width=1200, height=1600` as in a viewport `<meta>`.
+fn parse_viewport(content: &str) -> Option<(u32, u32)> {
+    let mut width = None;
+    let mut height = None;
+    for part in content.split([',', ';']) {
+        let mut pair = part.splitn(2, '=');
+        let key = pair.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let value = pair
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_end_matches("px")
+            .parse::<f32>()
+            .ok()
+            .filter(|v| *v >= 1.0)
+            .map(|v| v.round() as u32);
+        match key.as_str() {
+            "width" => width = value,
+            "height" => height = value,
+            _ => {}
+        }
+    }
+    width.zip(height)
+}
+
+fn dimension(value: Option<&str>) -> Option<u32> {
+    value
+        .and_then(|v| v.trim().trim_end_matches("px").parse::<f32>().ok())
+        .filter(|v| *v >= 1.0)
+        .map(|v| v.round() as u32)
+}
+
+/// The size a fixed-layout page is drawn at: its viewport `<meta>`, or for
+/// pages that are just an SVG or an image, the size of that.
+pub fn viewport(document: &Html, path: &str) -> (u32, u32) {
+    let meta = Selector::parse("meta[name=viewport]").unwrap();
+    let declared = document
+        .select(&meta)
+        .filter_map(|m| m.value().attr("content"))
+        .find_map(parse_viewport);
+    if let Some(size) = declared {
+        return size;
+    }
+
+    let svg = Selector::parse("body svg").unwrap();
+    let from_svg = document.select(&svg).next().and_then(|svg| {
+        let view_box = svg.value().attr("viewBox").and_then(|v| {
+            let numbers: Vec<&str> = v.split([' ', ',']).filter(|n| !n.is_empty()).collect();
+            match numbers.as_slice() {
+                [_, _, width, height] => dimension(Some(width)).zip(dimension(Some(height))),
+                _ => None,
+            }
+        });
+        view_box.or_else(|| {
+            dimension(svg.value().attr("width")).zip(dimension(svg.value().attr("height")))
+        })
+    });
+    let img = Selector::parse("body img").unwrap();
+    let from_img = || {
+        document.select(&img).next().and_then(|img| {
+            dimension(img.value().attr("width")).zip(dimension(img.value().attr("height")))
+        })
+    };
+    from_svg.or_else(from_img).unwrap_or_else(|| {
+        warn!(
+            "{} is fixed-layout but declares no viewport, using {}x{}",
+            path, DEFAULT_VIEWPORT.0, DEFAULT_VIEWPORT.1
+        );
+        DEFAULT_VIEWPORT
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_viewport, viewport, DEFAULT_VIEWPORT};
+    use scraper::Html;
+
+    #[test]
+    fn parses_viewport_meta_content() {
+        assert_eq!(parse_viewport("width=1200, height=1600"), Some((1200, 1600)));
+        assert_eq!(parse_viewport("height=800px;width=600.4px"), Some((600, 800)));
+        assert_eq!(parse_viewport(" WIDTH = 300 , Height = 400 "), Some((300, 400)));
+        assert_eq!(
+            parse_viewport("width=768, height=1024, initial-scale=1"),
+            Some((768, 1024))
+        );
+    }
+
+    #[test]
+    fn rejects_incomplete_viewports() {
+        assert_eq!(parse_viewport("width=device-width, initial-scale=1"), None);
+        assert_eq!(parse_viewport("width=1200"), None);
+        assert_eq!(parse_viewport("width=0, height=100"), None);
+        assert_eq!(parse_viewport(""), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_page_svg_or_image() {
+        let svg = Html::parse_document(concat!(
+            "<html><body><svg viewBox=\"0 0 1000 1500\" width=\"10\" height=\"10\">",
+            "</svg></body></html>"
+        ));
+        assert_eq!(viewport(&svg, "p.xhtml"), (1000, 1500));
+        let img = Html::parse_document(
+            "<html><body><img src=\"p.jpg\" width=\"640\" height=\"480\"></body></html>",
+        );
+        assert_eq!(viewport(&img, "p.xhtml"), (640, 480));
+        let empty = Html::parse_document("<html><body><p>text</p></body></html>");
+        assert_eq!(viewport(&empty, "p.xhtml"), DEFAULT_VIEWPORT);
+    }
+}
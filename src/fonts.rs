@@ -0,0 +1,543 @@
+// Embedded font handling.
+//
+// Vendors obfuscate embedded fonts so they can't simply be unzipped and
+// reused. `META-INF/encryption.xml` lists the obfuscated files and the
+// algorithm: the IDPF one XORs the first 1040 bytes with the SHA-1 of the
+// package unique identifier, Adobe's XORs the first 1024 bytes with the
+// 16 bytes of the book's UUID. Anything else listed there is real
+// encryption (DRM) that we can't and don't undo.
+//
+// Embedded OpenType fonts for Indic scripts run to megabytes, so each one
+// is subset to the characters of the text its `@font-face` families are
+// used for and re-encoded as WOFF2.
+
+use epub::archive::EpubArchive;
+use epub::doc::EpubDoc;
+use scraper::{Html, Selector};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use ttf_parser::gsub::{SingleSubstitution, SubstitutionSubtable};
+use ttf_parser::{Face, GlyphId, Tag};
+use xml::reader::{EventReader, XmlEvent};
+
+use super::css::{self, percent_decode};
+use super::metadata::BookMetadata;
+use super::woff2;
+
+pub const IDPF_ALGORITHM: &str = "http://www.idpf.org/2008/embedding";
+pub const ADOBE_ALGORITHM: &str = "http://ns.adobe.com/pdf/enc#RC";
+
+const IDPF_LENGTH: usize = 1040;
+const ADOBE_LENGTH: usize = 1024;
+
+/// Encryption algorithm of each file listed in encryption.xml, keyed by
+/// its decoded path in the archive.
+pub fn parse_encryption(xml: &[u8]) -> HashMap<String, String> {
+    let mut files = HashMap::new();
+    let mut algorithm = String::new();
+    for event in EventReader::new(xml) {
+        match event {
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) => {
+                let attribute = |wanted: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == wanted)
+                        .map(|a| a.value.clone())
+                        .unwrap_or_default()
+                };
+                match name.local_name.as_str() {
+                    "EncryptedData" => algorithm.clear(),
+                    "EncryptionMethod" => algorithm = attribute("Algorithm"),
+                    "CipherReference" => {
+                        let uri = percent_decode(&attribute("URI"));
+                        files.insert(uri.trim_start_matches('/').to_string(), algorithm.clone());
+                    }
+                    _ => {}
+                }
+            }
+            Err(e) => {
+                warn!("Can't parse encryption.xml: {}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+    files
+}
+
+pub fn is_obfuscation(algorithm: &str) -> bool {
+    algorithm == IDPF_ALGORITHM || algorithm == ADOBE_ALGORITHM
+}
+
+fn idpf_key(unique_identifier: &str) -> Vec<u8> {
+    let identifier: String = unique_identifier
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+        .collect();
+    sha1_smol::Sha1::from(identifier).digest().bytes().to_vec()
+}
+
+fn adobe_key(uuid: &str) -> Option<Vec<u8>> {
+    let hex: String = uuid
+        .trim()
+        .trim_start_matches("urn:uuid:")
+        .chars()
+        .filter(|c| *c != '-')
+        .collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    (0..16)
+        .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+fn xor(data: &mut [u8], key: &[u8], length: usize) {
+    for (i, byte) in data.iter_mut().take(length).enumerate() {
+        *byte ^= key[i % key.len()];
+    }
+}
+
+/// Files listed in encryption.xml, with what's needed to undo obfuscation.
+#[derive(Default)]
+pub struct Encryption {
+    pub files: HashMap<String, String>,
+    unique_identifier: String,
+    uuid: String,
+}
+
+impl Encryption {
+    /// Reads `META-INF/encryption.xml` from `epub`, if it has one.
+    pub fn read(epub: &str, metadata: &BookMetadata) -> Encryption {
+        let files = EpubArchive::new(epub)
+            .and_then(|mut archive| archive.get_entry("META-INF/encryption.xml"))
+            .map(|xml| parse_encryption(&xml))
+            .unwrap_or_default();
+        Encryption {
+            files,
+            unique_identifier: metadata.unique_identifier.clone(),
+            uuid: metadata.uuid.clone(),
+        }
+    }
+
+    /// Files that are encrypted with something other than font obfuscation.
+    pub fn drm_files(&self) -> Vec<&String> {
+        let mut files: Vec<&String> = self
+            .files
+            .iter()
+            .filter(|(_, algorithm)| !is_obfuscation(algorithm))
+            .map(|(path, _)| path)
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Undoes font obfuscation on `data` if `path` is obfuscated. Files that
+    /// are really encrypted are left alone, see `drm_files`.
+    pub fn decode(&self, path: &str, data: &mut [u8]) {
+        let algorithm = match self.files.get(&percent_decode(path)) {
+            Some(algorithm) => algorithm,
+            None => return,
+        };
+        match algorithm.as_str() {
+            IDPF_ALGORITHM => {
+                debug!("deobfuscating {} (IDPF)", path);
+                xor(data, &idpf_key(&self.unique_identifier), IDPF_LENGTH);
+            }
+            ADOBE_ALGORITHM => match adobe_key(&self.uuid) {
+                Some(key) => {
+                    debug!("deobfuscating {} (Adobe)", path);
+                    xor(data, &key, ADOBE_LENGTH);
+                }
+                None => warn!(
+                    "{} uses Adobe font obfuscation but the book has no UUID identifier, the font will be broken",
+                    path
+                ),
+            },
+            _ => {}
+        }
+    }
+}
+
+// Kept in every subset: what CSS `content`, list counters and the shaper's
+// dotted circle and joiners may need besides the text itself.
+fn always_kept() -> impl Iterator<Item = char> {
+    (' '..='~').chain(
+        ['\u{a0}', '\u{ad}', '\u{200c}', '\u{200d}', '\u{25cc}']
+            .iter()
+            .cloned(),
+    )
+}
+
+// Tables that subsetting would drop or break: variations, colour and
+// bitmap glyphs. Fonts with them are only re-encoded.
+const UNSUBSETTABLE_TABLES: &[&[u8; 4]] = &[b"fvar", b"COLR", b"CBDT", b"sbix", b"SVG ", b"EBDT"];
+
+// Layout tables the subsetter leaves out. Glyph ids don't change when
+// subsetting, so the original tables still apply.
+const LAYOUT_TABLES: &[&[u8; 4]] = &[b"GDEF", b"GPOS", b"GSUB", b"kern", b"BASE", b"MATH"];
+
+// Family names in a `font-family` or `font` value, lowercase and unquoted.
+fn family_names(property: &str, value: &str) -> Vec<String> {
+    let value = if property == "font" {
+        // the families come after the size: `bold 1.2em/1.5 "Family", serif`
+        let size = match value.find(|c: char| c.is_ascii_digit()) {
+            Some(size) => &value[size..],
+            None => return vec![],
+        };
+        match size.find(char::is_whitespace) {
+            Some(end) => &size[end..],
+            None => return vec![],
+        }
+    } else {
+        value
+    };
+    value
+        .split(',')
+        .map(|name| {
+            name.trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" ")
+                .to_lowercase()
+        })
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// Families set by a rule block, limited to `families`.
+fn block_families(block: &str, families: &HashSet<String>) -> Vec<String> {
+    css::declarations(block)
+        .iter()
+        .filter(|(property, _)| property == "font-family" || property == "font")
+        .flat_map(|(property, value)| family_names(property, value))
+        .filter(|name| families.contains(name))
+        .collect()
+}
+
+// Embedded font files and the `@font-face` families that use them. Paths
+// are decoded archive paths.
+fn font_faces(stylesheets: &[(String, String)]) -> HashMap<String, HashSet<String>> {
+    let mut faces: HashMap<String, HashSet<String>> = HashMap::new();
+    for (path, stylesheet) in stylesheets {
+        for (prelude, block) in css::rules(stylesheet) {
+            if !prelude.to_lowercase().starts_with("@font-face") {
+                continue;
+            }
+            let declarations = css::declarations(&block);
+            let family = declarations
+                .iter()
+                .find(|(property, _)| property == "font-family")
+                .and_then(|(property, value)| family_names(property, value).into_iter().next());
+            let family = match family {
+                Some(family) => family,
+                None => continue,
+            };
+            for (_, src) in declarations
+                .iter()
+                .filter(|(property, _)| property == "src")
+            {
+                css::rewrite_urls(src, |reference| {
+                    let font = css::resolve_path(path, &percent_decode(reference));
+                    faces.entry(font).or_default().insert(family.clone());
+                    None
+                });
+            }
+        }
+    }
+    faces
+}
+
+fn add_text(chars: &mut HashSet<char>, text: &str) {
+    for c in text.chars() {
+        chars.insert(c);
+        // for `text-transform`
+        chars.extend(c.to_uppercase());
+        chars.extend(c.to_lowercase());
+    }
+}
+
+// Characters each of `families` is used for across all chapters. Every
+// stylesheet is applied to every chapter, and rules scraper can't match
+// count for the whole chapter, so this errs on the side of too many.
+fn used_characters(
+    families: &HashSet<String>,
+    stylesheets: &[(String, String)],
+    chapters: &[Html],
+) -> HashMap<String, HashSet<char>> {
+    let mut used: HashMap<String, HashSet<char>> = HashMap::new();
+    let mut rules = vec![];
+    let mut generated = HashSet::new();
+    for (_, stylesheet) in stylesheets {
+        for (prelude, block) in css::rules(stylesheet) {
+            if prelude.starts_with('@') {
+                continue;
+            }
+            for (property, value) in css::declarations(&block) {
+                if property == "content" {
+                    add_text(&mut generated, &value);
+                }
+            }
+            let named = block_families(&block, families);
+            if !named.is_empty() {
+                rules.push((Selector::parse(&prelude).ok(), named));
+            }
+        }
+    }
+
+    let styled = Selector::parse("[style]").unwrap();
+    for chapter in chapters {
+        let mut matched = vec![];
+        for (selector, named) in rules.iter() {
+            match selector {
+                Some(selector) => {
+                    for element in chapter.select(selector) {
+                        matched.push((element.text().collect::<String>(), named.clone()));
+                    }
+                }
+                None => matched.push((chapter.root_element().text().collect(), named.clone())),
+            }
+        }
+        for element in chapter.select(&styled) {
+            let style = element.value().attr("style").unwrap_or_default();
+            let named = block_families(style, families);
+            if !named.is_empty() {
+                matched.push((element.text().collect(), named));
+            }
+        }
+        for (text, named) in matched {
+            for family in named {
+                add_text(used.entry(family).or_default(), &text);
+            }
+        }
+    }
+
+    for chars in used.values_mut() {
+        chars.extend(generated.iter());
+    }
+    used
+}
+
+// What a GSUB subtable can turn `glyph` into, given the glyphs in `glyphs`.
+fn substitutes(
+    subtable: &SubstitutionSubtable,
+    glyph: GlyphId,
+    glyphs: &BTreeSet<u16>,
+) -> Vec<u16> {
+    match subtable {
+        SubstitutionSubtable::Single(SingleSubstitution::Format1 { coverage, delta }) => {
+            if coverage.contains(glyph) {
+                vec![glyph.0.wrapping_add(*delta as u16)]
+            } else {
+                vec![]
+            }
+        }
+        SubstitutionSubtable::Single(SingleSubstitution::Format2 {
+            coverage,
+            substitutes,
+        }) => coverage
+            .get(glyph)
+            .and_then(|i| substitutes.get(i))
+            .map(|g| vec![g.0])
+            .unwrap_or_default(),
+        SubstitutionSubtable::Multiple(s) => s
+            .coverage
+            .get(glyph)
+            .and_then(|i| s.sequences.get(i))
+            .map(|sequence| sequence.substitutes.into_iter().map(|g| g.0).collect())
+            .unwrap_or_default(),
+        SubstitutionSubtable::Alternate(s) => s
+            .coverage
+            .get(glyph)
+            .and_then(|i| s.alternate_sets.get(i))
+            .map(|set| set.alternates.into_iter().map(|g| g.0).collect())
+            .unwrap_or_default(),
+        SubstitutionSubtable::Ligature(s) => s
+            .coverage
+            .get(glyph)
+            .and_then(|i| s.ligature_sets.get(i))
+            .map(|set| {
+                set.into_iter()
+                    .filter(|ligature| {
+                        ligature
+                            .components
+                            .into_iter()
+                            .all(|component| glyphs.contains(&component.0))
+                    })
+                    .map(|ligature| ligature.glyph.0)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        SubstitutionSubtable::ReverseChainSingle(s) => s
+            .coverage
+            .get(glyph)
+            .and_then(|i| s.substitutes.get(i))
+            .map(|g| vec![g.0])
+            .unwrap_or_default(),
+        // contextual lookups only call other lookups, and those are all
+        // applied anyway
+        SubstitutionSubtable::Context(_) | SubstitutionSubtable::ChainContext(_) => vec![],
+    }
+}
+
+// Adds every glyph GSUB can substitute for the ones in `glyphs`, so
+// conjuncts and contextual forms survive subsetting. Lookups are applied
+// regardless of feature and context, which keeps more than needed.
+fn gsub_closure(face: &Face, glyphs: &mut BTreeSet<u16>) {
+    let gsub = match face.tables().gsub {
+        Some(gsub) => gsub,
+        None => return,
+    };
+    loop {
+        let before = glyphs.len();
+        for lookup in gsub.lookups {
+            for subtable in lookup.subtables.into_iter::<SubstitutionSubtable>() {
+                let found: Vec<u16> = glyphs
+                    .iter()
+                    .flat_map(|glyph| substitutes(&subtable, GlyphId(*glyph), glyphs))
+                    .collect();
+                glyphs.extend(found);
+            }
+        }
+        if glyphs.len() == before {
+            break;
+        }
+    }
+}
+
+// Subsets an OpenType font to `chars` and encodes it as WOFF2.
+fn subset_font(data: &[u8], chars: &HashSet<char>) -> Result<Vec<u8>, String> {
+    let face = Face::parse(data, 0).map_err(|e| e.to_string())?;
+    let raw_table = |tag: &[u8; 4]| face.raw_face().table(Tag::from_bytes(tag));
+    if UNSUBSETTABLE_TABLES
+        .iter()
+        .any(|tag| raw_table(tag).is_some())
+    {
+        debug!("font can't be subset, only converting it");
+        let (flavor, tables) = woff2::read_sfnt(data)?;
+        return woff2::encode(flavor, tables);
+    }
+
+    let mut glyphs: BTreeSet<u16> = chars
+        .iter()
+        .filter_map(|c| face.glyph_index(*c))
+        .map(|glyph| glyph.0)
+        .collect();
+    glyphs.insert(0);
+    gsub_closure(&face, &mut glyphs);
+    let glyphs: Vec<u16> = glyphs.into_iter().collect();
+    trace!(
+        "keeping {} of {} glyphs",
+        glyphs.len(),
+        face.number_of_glyphs()
+    );
+
+    let subset = subsetter::subset(data, 0, subsetter::Profile::pdf(&glyphs))
+        .map_err(|e| format!("can't subset: {}", e))?;
+    let (flavor, mut tables) = woff2::read_sfnt(&subset)?;
+    for tag in LAYOUT_TABLES {
+        if let Some(table) = raw_table(tag) {
+            tables.push((**tag, table.to_vec()));
+        }
+    }
+    woff2::encode(flavor, tables)
+}
+
+fn is_opentype(path: &str) -> bool {
+    match Path::new(path).extension().and_then(OsStr::to_str) {
+        Some(ext) => ext.eq_ignore_ascii_case("otf") || ext.eq_ignore_ascii_case("ttf"),
+        None => false,
+    }
+}
+
+/// Converts the OpenType fonts that `@font-face` rules use into WOFF2
+/// files in `resources/`, subset to the characters the book needs them
+/// for. Returns their output paths keyed by decoded archive path; fonts
+/// that can't be converted are left out, to be copied as they are.
+pub fn convert_fonts(
+    epub: &str,
+    output_root: &Path,
+    encryption: &Encryption,
+) -> HashMap<String, String> {
+    let mut converted = HashMap::new();
+    let mut doc = EpubDoc::new(epub).expect("Can't open ePub");
+    let resources = doc.resources.clone();
+
+    let mut stylesheets = vec![];
+    let mut chapters = vec![];
+    let style = Selector::parse("style").unwrap();
+    for (key, (path, mime)) in resources.iter() {
+        let path = percent_decode(&path.to_string_lossy());
+        if mime.contains("css") {
+            if let Ok(stylesheet) = doc.get_resource_str(key) {
+                stylesheets.push((path, stylesheet));
+            }
+        } else if mime.contains("html") {
+            if let Ok(html) = doc.get_resource_str(key) {
+                let chapter = Html::parse_document(&html);
+                for element in chapter.select(&style) {
+                    stylesheets.push((path.clone(), element.text().collect()));
+                }
+                chapters.push(chapter);
+            }
+        }
+    }
+
+    let faces = font_faces(&stylesheets);
+    let mut fonts: Vec<&String> = resources
+        .iter()
+        .filter(|(_, (path, _))| {
+            let path = path.to_string_lossy();
+            is_opentype(&path) && faces.contains_key(&percent_decode(&path))
+        })
+        .map(|(key, _)| key)
+        .collect();
+    fonts.sort();
+    if fonts.is_empty() {
+        return converted;
+    }
+    info!("Subsetting {} fonts...", fonts.len());
+
+    let families = faces.values().flatten().cloned().collect();
+    let used = used_characters(&families, &stylesheets, &chapters);
+    for key in fonts {
+        let path = resources[key].0.to_str().unwrap_or_default();
+        let decoded = percent_decode(path);
+        let mut data = match doc.get_resource(key) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        encryption.decode(path, &mut data);
+
+        let mut chars: HashSet<char> = always_kept().collect();
+        for family in faces[&decoded].iter() {
+            chars.extend(used.get(family).into_iter().flatten());
+        }
+        match subset_font(&data, &chars) {
+            Ok(woff2) => {
+                let stem = Path::new(&decoded)
+                    .file_stem()
+                    .and_then(OsStr::to_str)
+                    .unwrap_or_default()
+                    .to_string();
+                let output = format!("resources/{}.woff2", stem);
+                debug!(
+                    "{}: {} characters, {} -> {} bytes",
+                    decoded,
+                    chars.len(),
+                    data.len(),
+                    woff2.len()
+                );
+                fs::write(output_root.join(&output), woff2).expect("Can't write font");
+                converted.insert(decoded, output);
+            }
+            Err(e) => warn!("Can't convert font {}, copying it as is: {}", decoded, e),
+        }
+    }
+    converted
+}
@@ -0,0 +1,800 @@
+// GIF and SVG images.
+//
+// `compress_image_resource` goes through the image crate, which would drop
+// the animation of a GIF when resizing it and can't read SVG at all. GIFs
+// are copied as they are, or turned into animated WebP when the book asks
+// for it. SVGs are served from the same origin as the reader, so scripts,
+// event handlers and references to anything outside the book are stripped
+// from them first.
+
+use epub::doc::EpubDoc;
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPEncoder;
+use image::{AnimationDecoder, ExtendedColorType, ImageDecoder, RgbaImage};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent};
+use xml::writer::EmitterConfig;
+
+use super::css::{self, percent_decode};
+
+/// Elements dropped from SVGs with everything inside them.
+const DROPPED_ELEMENTS: &[&str] = &[
+    "script", "handler", "listener", "iframe", "frame", "frameset", "object", "embed",
+    "applet", "base", "link", "meta", "form",
+];
+
+/// Elements that change attributes after load. Pointed at `href` they
+/// swap in a link the sanitizer never saw, so those are dropped.
+const ANIMATION_ELEMENTS: &[&str] = &[
+    "set", "animate", "animatecolor", "animatemotion", "animatetransform",
+];
+
+/// Attributes holding the values an animation sets.
+const ANIMATION_VALUES: &[&str] = &["to", "from", "by", "values"];
+
+/// The only data URIs kept: raster images can't run anything.
+const IMAGE_DATA: &[&str] = &[
+    "data:image/png", "data:image/jpeg", "data:image/jpg", "data:image/gif", "data:image/webp",
+];
+
+/// Browsers play GIF frames of 10ms or less at 100ms, WebP ones as given.
+const MIN_FRAME_DELAY: u32 = 10;
+const DEFAULT_FRAME_DELAY: u32 = 100;
+
+fn push_u24(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+fn push_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn riff(chunks: Vec<u8>) -> Vec<u8> {
+    let mut webp = Vec::with_capacity(chunks.len() + 12);
+    webp.extend_from_slice(b"RIFF");
+    webp.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    webp.extend_from_slice(b"WEBP");
+    webp.extend_from_slice(&chunks);
+    webp
+}
+
+fn encode_lossless(frame: &RgbaImage) -> Result<Vec<u8>, String> {
+    let mut webp = vec![];
+    WebPEncoder::new_lossless(&mut webp)
+        .encode(frame.as_raw(), frame.width(), frame.height(), ExtendedColorType::Rgba8)
+        .map_err(|e| format!("can't encode frame: {}", e))?;
+    Ok(webp)
+}
+
+// The `VP8L` chunk of a still WebP file, header included, to be put in an
+// `ANMF` frame.
+fn bitstream_chunk(webp: &[u8]) -> Result<&[u8], String> {
+    let mut offset = 12;
+    while offset + 8 <= webp.len() {
+        let size = u32::from_le_bytes([
+            webp[offset + 4],
+            webp[offset + 5],
+            webp[offset + 6],
+            webp[offset + 7],
+        ]) as usize;
+        let end = (offset + 8 + size + size % 2).min(webp.len());
+        if &webp[offset..offset + 4] == b"VP8L" {
+            return Ok(&webp[offset..end]);
+        }
+        offset = end;
+    }
+    Err("encoder wrote no VP8L chunk".to_string())
+}
+
+// How many times a GIF plays, as WebP counts it: 0 for forever. GIFs
+// without a NETSCAPE2.0 extension play once, ones with a count of `n`
+// repeat `n` times after the first.
+fn loop_count(gif: &[u8]) -> u16 {
+    let marker = b"NETSCAPE2.0";
+    gif.windows(marker.len())
+        .position(|window| window == marker)
+        .and_then(|i| gif.get(i + marker.len()..i + marker.len() + 4))
+        .filter(|block| block[0] == 3 && block[1] == 1)
+        .map(|block| match u16::from_le_bytes([block[2], block[3]]) {
+            0 => 0,
+            n => n.saturating_add(1),
+        })
+        .unwrap_or(1)
+}
+
+/// Converts a GIF to lossless WebP, animated if the GIF has more than one
+/// frame.
+pub fn gif_to_webp(gif: &[u8]) -> Result<Vec<u8>, String> {
+    let decoder = GifDecoder::new(Cursor::new(gif)).map_err(|e| e.to_string())?;
+    let (width, height) = decoder.dimensions();
+    let frames = decoder
+        .into_frames()
+        .collect_frames()
+        .map_err(|e| e.to_string())?;
+
+    // the decoder hands out every frame composited onto the full canvas,
+    // so identical ones in a row only need to be shown longer
+    let mut timeline: Vec<(RgbaImage, u32)> = vec![];
+    for frame in frames {
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        let mut delay = numerator / denominator.max(1);
+        if delay <= MIN_FRAME_DELAY {
+            delay = DEFAULT_FRAME_DELAY;
+        }
+        let buffer = frame.into_buffer();
+        match timeline.last_mut() {
+            Some((previous, duration)) if *previous == buffer => *duration += delay,
+            _ => timeline.push((buffer, delay)),
+        }
+    }
+    if timeline.len() < 2 {
+        let frame = timeline.pop().ok_or_else(|| "GIF has no frames".to_string())?;
+        return encode_lossless(&frame.0);
+    }
+
+    let mut chunks = vec![];
+    let mut vp8x = vec![0x10 | 0x02, 0, 0, 0]; // alpha, animation
+    push_u24(&mut vp8x, width - 1);
+    push_u24(&mut vp8x, height - 1);
+    push_chunk(&mut chunks, b"VP8X", &vp8x);
+
+    let mut anim = vec![0, 0, 0, 0]; // transparent background
+    anim.extend_from_slice(&loop_count(gif).to_le_bytes());
+    push_chunk(&mut chunks, b"ANIM", &anim);
+
+    for (frame, duration) in timeline.iter() {
+        let mut anmf = vec![];
+        push_u24(&mut anmf, 0); // x / 2
+        push_u24(&mut anmf, 0); // y / 2
+        push_u24(&mut anmf, frame.width() - 1);
+        push_u24(&mut anmf, frame.height() - 1);
+        push_u24(&mut anmf, (*duration).min(0xff_ffff));
+        anmf.push(0x02); // no blending, no disposal
+        anmf.extend_from_slice(bitstream_chunk(&encode_lossless(frame)?)?);
+        push_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+    Ok(riff(chunks))
+}
+
+/// Writes an animated WebP for every GIF in the book that comes out
+/// smaller, returning their output paths keyed by decoded path in the
+/// ePub. The others are copied as they are.
+pub fn convert_gifs(epub: &str, output_root: &Path) -> HashMap<String, String> {
+    let mut converted = HashMap::new();
+    let mut doc = EpubDoc::new(epub).expect("Can't open ePub");
+    let mut gifs: Vec<(String, String)> = doc
+        .resources
+        .iter()
+        .filter(|(_, (_, mime))| mime.contains("gif"))
+        .map(|(key, (path, _))| (key.clone(), path.to_string_lossy().into_owned()))
+        .collect();
+    gifs.sort();
+    for (key, path) in gifs {
+        let data = match doc.get_resource(&key) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        match gif_to_webp(&data) {
+            Ok(webp) if webp.len() < data.len() => {
+                debug!("{}: {} -> {} bytes as WebP", path, data.len(), webp.len());
+                let output = format!("images/{}.webp", key);
+                std::fs::write(output_root.join(&output), webp).expect("Can't write WebP");
+                converted.insert(percent_decode(&path), output);
+            }
+            Ok(webp) => debug!(
+                "{}: WebP would be {} bytes, keeping the {} byte GIF",
+                path,
+                webp.len(),
+                data.len()
+            ),
+            Err(e) => warn!("Can't convert {} to WebP, copying it as is: {}", path, e),
+        }
+    }
+    converted
+}
+
+fn local_name(name: &xml::name::OwnedName) -> String {
+    name.local_name.to_ascii_lowercase()
+}
+
+fn is_external(reference: &str) -> bool {
+    let reference = reference.trim();
+    !reference.starts_with('#')
+        && !reference.starts_with("data:")
+        && (reference.starts_with('/') || reference.contains(':'))
+}
+
+// Whether a stylesheet still loads anything from outside the book after
+// its local references were rewritten.
+fn has_external_reference(style: &str) -> bool {
+    let lower = style.to_ascii_lowercase();
+    lower.contains("@import")
+        || lower.match_indices("url(").any(|(i, _)| {
+            let inner = &lower[i + "url(".len()..];
+            let inner = inner.split(')').next().unwrap_or_default();
+            is_external(inner.trim().trim_matches(|c| c == '"' || c == '\''))
+        })
+}
+
+// Script URLs, and data URIs other than raster images, which could carry
+// a document of their own.
+fn is_unsafe_url(value: &str) -> bool {
+    // browsers skip tabs and newlines in URLs, `java\tscript:` runs too
+    let url = value
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if url.starts_with("data:") {
+        return !IMAGE_DATA.iter().any(|prefix| url.starts_with(prefix));
+    }
+    url.starts_with("javascript:") || url.starts_with("vbscript:")
+}
+
+// Whether an animation element sets `href` or `xlink:href`.
+fn animates_href<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(attributes: I) -> bool {
+    attributes.into_iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("attributename")
+            && value.rsplit(':').next().unwrap_or_default().trim().eq_ignore_ascii_case("href")
+    })
+}
+
+// What an `href` or `src` in an SVG becomes: fragments and image data URIs
+// stay, links to other files of the book are pointed at their output,
+// anything else is dropped except for web links on `<a>`.
+fn sanitize_reference<F: Fn(&str) -> Option<String>>(
+    element: &str,
+    value: &str,
+    locate: &F,
+) -> Option<String> {
+    let value = value.trim();
+    let lower = value.to_ascii_lowercase();
+    if is_unsafe_url(value) {
+        return None;
+    }
+    if value.starts_with('#') || lower.starts_with("data:") {
+        return Some(value.to_string());
+    }
+    if element == "a"
+        && ["http:", "https:", "mailto:"]
+            .iter()
+            .any(|scheme| lower.starts_with(scheme))
+    {
+        return Some(value.to_string());
+    }
+    if is_external(value) {
+        return None;
+    }
+    let (file, suffix) = match value.find(['?', '#']) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    locate(file).map(|found| format!("{}{}", found, suffix))
+}
+
+// What an attribute of `element` becomes, `None` to drop it. `name` is the
+// lowercase local name.
+fn sanitize_attribute<L, S>(
+    element: &str,
+    name: &str,
+    value: &str,
+    locate: &L,
+    rewrite_style: &S,
+) -> Option<String>
+where
+    L: Fn(&str) -> Option<String>,
+    S: Fn(&str) -> Option<String>,
+{
+    if name.starts_with("on") {
+        return None;
+    }
+    match name {
+        "href" | "src" => sanitize_reference(element, value, locate),
+        "style" => rewrite_style(value),
+        _ if ANIMATION_VALUES.contains(&name) && value.split(';').any(is_unsafe_url) => None,
+        _ => Some(value.to_string()),
+    }
+}
+
+/// Strips scripts, event handlers and external references from an SVG.
+/// References to other files of the book are pointed at their output
+/// paths, `output` being where the SVG itself is written. SVGs xml-rs
+/// can't read are stripped as text instead.
+pub fn sanitize_svg(
+    svg: &[u8],
+    path: &str,
+    output: &str,
+    outputs: &HashMap<String, String>,
+) -> Vec<u8> {
+    let path = percent_decode(path);
+    let locate = |reference: &str| {
+        let target = css::resolve_path(&path, &percent_decode(reference));
+        outputs
+            .get(&target)
+            .map(|found| css::relative_path(output, found))
+    };
+    let rewrite_style = |style: &str| {
+        let style = css::rewrite_urls(style, locate);
+        if has_external_reference(&style) {
+            warn!("{}: dropping a style with external references", path);
+            None
+        } else {
+            Some(style)
+        }
+    };
+    match sanitize_svg_xml(svg, &path, &locate, &rewrite_style) {
+        Ok(sanitized) => sanitized,
+        Err(e) => {
+            warn!("Can't parse {} ({}), stripping it as text", path, e);
+            sanitize_svg_text(&String::from_utf8_lossy(svg), &path, &locate, &rewrite_style)
+                .into_bytes()
+        }
+    }
+}
+
+fn sanitize_svg_xml<L, S>(
+    svg: &[u8],
+    path: &str,
+    locate: &L,
+    rewrite_style: &S,
+) -> Result<Vec<u8>, String>
+where
+    L: Fn(&str) -> Option<String>,
+    S: Fn(&str) -> Option<String>,
+{
+    let mut sanitized = vec![];
+    let mut writer = EmitterConfig::new()
+        .perform_indent(false)
+        .write_document_declaration(true)
+        .create_writer(&mut sanitized);
+    let mut skipped_depth = 0;
+    let mut in_style = false;
+    for event in EventReader::new(svg) {
+        let mut event = event.map_err(|e| e.to_string())?;
+        if skipped_depth > 0 {
+            match event {
+                XmlEvent::StartElement { .. } => skipped_depth += 1,
+                XmlEvent::EndElement { .. } => skipped_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        match event {
+            XmlEvent::StartElement {
+                ref name,
+                ref mut attributes,
+                ..
+            } => {
+                let element = local_name(name);
+                let animation = ANIMATION_ELEMENTS.contains(&element.as_str())
+                    && animates_href(
+                        attributes
+                            .iter()
+                            .map(|a| (a.name.local_name.as_str(), a.value.as_str())),
+                    );
+                if DROPPED_ELEMENTS.contains(&element.as_str()) || animation {
+                    debug!("{}: dropping <{}>", path, element);
+                    skipped_depth = 1;
+                    continue;
+                }
+                in_style = element == "style";
+                let mut kept = vec![];
+                for mut attribute in attributes.drain(..) {
+                    let attribute_name = local_name(&attribute.name);
+                    match sanitize_attribute(
+                        &element,
+                        &attribute_name,
+                        &attribute.value,
+                        locate,
+                        rewrite_style,
+                    ) {
+                        Some(value) => attribute.value = value,
+                        None => {
+                            debug!("{}: dropping {}=\"{}\"", path, attribute_name, attribute.value);
+                            continue;
+                        }
+                    }
+                    kept.push(attribute);
+                }
+                *attributes = kept;
+            }
+            XmlEvent::EndElement { .. } => in_style = false,
+            XmlEvent::Characters(ref mut text) | XmlEvent::CData(ref mut text) if in_style => {
+                *text = rewrite_style(text).unwrap_or_default();
+            }
+            // `<?xml-stylesheet?>` would load a stylesheet
+            XmlEvent::ProcessingInstruction { .. } => continue,
+            _ => {}
+        }
+        if let Some(event) = event.as_writer_event() {
+            writer.write(event).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(sanitized)
+}
+
+// `&amp;`, `&quot;` and numeric references decoded, so `&#106;avascript:`
+// is seen for what it is. Other entities are left as they are.
+fn decode_references(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end < 12 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let name = &rest[1..end];
+        let c = match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match name.strip_prefix('#') {
+                Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32),
+                Some(decimal) => decimal.parse().ok().and_then(std::char::from_u32),
+                None => None,
+            },
+        };
+        match c {
+            Some(c) => decoded.push(c),
+            None => decoded.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// `text` without references to entities other than the predefined ones.
+// Those would come from a DTD subset, which the text fallback drops.
+fn drop_entity_references(text: &str) -> String {
+    let mut kept = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        kept.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let name_end = rest
+            .find(|c: char| !(c.is_alphanumeric() || "#-_.:".contains(c)))
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        let predefined = ["amp", "lt", "gt", "quot", "apos"].contains(&name);
+        if rest[name_end..].starts_with(';') && !name.is_empty() {
+            if predefined || name.starts_with('#') {
+                kept.push('&');
+                kept.push_str(name);
+                kept.push(';');
+            }
+            rest = &rest[name_end + 1..];
+        } else {
+            kept.push_str("&amp;");
+        }
+    }
+    kept.push_str(rest);
+    kept
+}
+
+// Where the tag starting `markup` ends, skipping `>` in quoted values.
+fn tag_end(markup: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in markup.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+// The name and attributes of a start tag, `<` and `>` included. Values
+// are as written, entity references and all.
+fn parse_tag(tag: &str) -> (&str, Vec<(&str, &str)>) {
+    let inner = tag
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim_end_matches('/');
+    let name_end = inner
+        .find(|c: char| c.is_whitespace() || c == '/')
+        .unwrap_or(inner.len());
+    let (name, mut rest) = inner.split_at(name_end);
+    let mut attributes = vec![];
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            break;
+        }
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let (attribute, after) = rest.split_at(end);
+        let after = after.trim_start();
+        if !after.starts_with('=') {
+            attributes.push((attribute, ""));
+            rest = after;
+            continue;
+        }
+        let after = after[1..].trim_start();
+        let (value, remaining) = match after.chars().next() {
+            Some(q) if q == '"' || q == '\'' => match after[1..].find(q) {
+                Some(close) => (&after[1..close + 1], &after[close + 2..]),
+                None => (&after[1..], ""),
+            },
+            _ => {
+                let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                after.split_at(end)
+            }
+        };
+        attributes.push((attribute, value));
+        rest = remaining;
+    }
+    (name, attributes)
+}
+
+// The fallback for SVGs xml-rs can't read, internal DTD subsets of some
+// Illustrator and InDesign exports among them: the markup is walked as
+// text and the same elements and attributes are dropped, so the image
+// still makes it into the book. The internal subset goes too, along with
+// references to the entities it declared.
+fn sanitize_svg_text<L, S>(svg: &str, path: &str, locate: &L, rewrite_style: &S) -> String
+where
+    L: Fn(&str) -> Option<String>,
+    S: Fn(&str) -> Option<String>,
+{
+    let mut sanitized = String::with_capacity(svg.len());
+    // the element being dropped and how many of it are open
+    let mut dropped: Option<(String, usize)> = None;
+    let mut in_style = false;
+    let mut rest = svg;
+    while !rest.is_empty() {
+        let start = rest.find('<').unwrap_or(rest.len());
+        let text = &rest[..start];
+        rest = &rest[start..];
+        if dropped.is_none() && !text.is_empty() {
+            let text = drop_entity_references(text);
+            if in_style {
+                sanitized.push_str(&rewrite_style(&text).unwrap_or_default());
+            } else {
+                sanitized.push_str(&text);
+            }
+        }
+        if rest.is_empty() {
+            break;
+        }
+
+        let end = if rest.starts_with("<!--") {
+            rest.find("-->").map(|i| i + 3)
+        } else if rest.starts_with("<![CDATA[") {
+            rest.find("]]>").map(|i| i + 3)
+        } else if rest.starts_with("<!") {
+            // a DOCTYPE, with its internal subset in brackets
+            match (rest.find('['), rest.find('>')) {
+                (Some(open), Some(close)) if open < close => {
+                    rest.find("]>").or_else(|| rest.find(']')).map(|i| {
+                        i + rest[i..].find('>').map_or(rest.len() - i, |j| j + 1)
+                    })
+                }
+                (_, close) => close.map(|i| i + 1),
+            }
+        } else if rest.starts_with("<?") {
+            rest.find("?>").map(|i| i + 2)
+        } else {
+            tag_end(rest)
+        }
+        .unwrap_or(rest.len());
+        let markup = &rest[..end];
+        rest = &rest[end..];
+
+        if markup.starts_with("<![CDATA[") {
+            if dropped.is_some() {
+                continue;
+            }
+            if in_style {
+                let inner = &markup["<![CDATA[".len()..markup.len().saturating_sub(3)];
+                sanitized.push_str("<![CDATA[");
+                sanitized.push_str(&rewrite_style(inner).unwrap_or_default());
+                sanitized.push_str("]]>");
+            } else {
+                sanitized.push_str(markup);
+            }
+        } else if markup.starts_with("<!") {
+            // the internal subset can declare entities holding markup,
+            // scripts included, so only the bare DOCTYPE stays
+            if dropped.is_none() {
+                match markup.find('[') {
+                    Some(open) => {
+                        sanitized.push_str(markup[..open].trim_end());
+                        sanitized.push('>');
+                    }
+                    None => sanitized.push_str(markup),
+                }
+            }
+        } else if markup.starts_with("<?") {
+            // `<?xml-stylesheet?>` would load a stylesheet
+            if dropped.is_none() && markup.starts_with("<?xml ") {
+                sanitized.push_str(markup);
+            }
+        } else if let Some(closing) = markup.strip_prefix("</") {
+            let name = closing.trim_end_matches('>').trim();
+            let local = name.rsplit(':').next().unwrap_or_default().to_ascii_lowercase();
+            if let Some((element, open)) = dropped.as_mut() {
+                if *element == local {
+                    *open -= 1;
+                    if *open == 0 {
+                        dropped = None;
+                    }
+                }
+                continue;
+            }
+            in_style = false;
+            sanitized.push_str(markup);
+        } else {
+            let self_closing = markup.trim_end_matches('>').trim_end().ends_with('/');
+            let (name, attributes) = parse_tag(markup);
+            let element = name.rsplit(':').next().unwrap_or_default().to_ascii_lowercase();
+            if let Some((dropping, open)) = dropped.as_mut() {
+                if *dropping == element && !self_closing {
+                    *open += 1;
+                }
+                continue;
+            }
+            let animation = ANIMATION_ELEMENTS.contains(&element.as_str())
+                && animates_href(attributes.iter().map(|(name, value)| {
+                    (name.rsplit(':').next().unwrap_or_default(), *value)
+                }));
+            if DROPPED_ELEMENTS.contains(&element.as_str()) || animation {
+                debug!("{}: dropping <{}>", path, element);
+                if !self_closing {
+                    dropped = Some((element, 1));
+                }
+                continue;
+            }
+            in_style = element == "style" && !self_closing;
+            sanitized.push('<');
+            sanitized.push_str(name);
+            for (attribute, raw) in attributes {
+                let local = attribute.rsplit(':').next().unwrap_or_default().to_ascii_lowercase();
+                let raw = drop_entity_references(raw);
+                let value = decode_references(&raw);
+                match sanitize_attribute(&element, &local, &value, locate, rewrite_style) {
+                    Some(kept) if kept == value => sanitized.push_str(&format!(
+                        " {}=\"{}\"",
+                        attribute,
+                        raw.replace('"', "&quot;")
+                    )),
+                    Some(kept) => sanitized.push_str(&format!(
+                        " {}=\"{}\"",
+                        attribute,
+                        kept.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;")
+                    )),
+                    None => debug!("{}: dropping {}=\"{}\"", path, attribute, raw),
+                }
+            }
+            sanitized.push_str(if self_closing { "/>" } else { ">" });
+        }
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitize(svg: &str) -> String {
+        let mut outputs = HashMap::new();
+        outputs.insert("OEBPS/images/pic.png".to_string(), "images/pic.png".to_string());
+        let sanitized = sanitize_svg(
+            svg.as_bytes(),
+            "OEBPS/images/a.svg",
+            "images/a.svg",
+            &outputs,
+        );
+        String::from_utf8(sanitized).unwrap()
+    }
+
+    #[test]
+    fn drops_scripts_and_handlers() {
+        let svg = sanitize(concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)">"#,
+            r#"<script>alert(2)</script><rect/></svg>"#,
+        ));
+        assert!(!svg.contains("alert"));
+        assert!(svg.contains("<rect"));
+    }
+
+    #[test]
+    fn drops_animations_of_href() {
+        let svg = sanitize(concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" "#,
+            r#"xmlns:xlink="http://www.w3.org/1999/xlink">"#,
+            r##"<a xlink:href="#x"><set attributeName="xlink:href" to="javascript:alert(1)"/>"##,
+            r##"<animate attributeName="href" values="#a;javascript:alert(2)"/>"##,
+            r#"<animate attributeName="opacity" from="0" to="1"/><text>go</text></a></svg>"#,
+        ));
+        assert!(!svg.contains("javascript"));
+        assert!(!svg.contains("<set"));
+        assert!(svg.contains("attributeName=\"opacity\""));
+    }
+
+    #[test]
+    fn rejects_script_and_document_urls() {
+        let svg = sanitize(concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg">"#,
+            r#"<a href="java&#9;script:alert(1)"><text>a</text></a>"#,
+            r#"<a href="data:text/html,x"><text>b</text></a>"#,
+            r#"<image href="data:image/png;base64,AA"/><image href="pic.png"/>"#,
+            r#"<animate attributeName="fill" to="javascript:alert(1)"/></svg>"#,
+        ));
+        assert!(!svg.contains("javascript"));
+        assert!(!svg.contains("data:text/html"));
+        assert!(svg.contains("data:image/png;base64,AA"));
+        assert!(svg.contains("href=\"pic.png\""));
+    }
+
+    #[test]
+    fn keeps_svgs_with_an_internal_subset() {
+        let svg = sanitize(concat!(
+            r#"<?xml version="1.0"?><!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "#,
+            r#""http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd" [ <!ENTITY ns_svg "#,
+            r#""http://www.w3.org/2000/svg"> ]><svg xmlns="&ns_svg;"><rect onclick="x()"/></svg>"#,
+        ));
+        assert!(svg.contains("<rect"));
+        assert!(!svg.contains("onclick"));
+    }
+
+    #[test]
+    fn strips_unparsable_svgs_as_text() {
+        let unparsable = concat!(
+            r#"<?xml version="1.0"?><!DOCTYPE svg [ <!ENTITY % p "x"> ]>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><text>&undefined;</text></svg>"#,
+        );
+        let keep = |value: &str| Some(value.to_string());
+        assert!(sanitize_svg_xml(unparsable.as_bytes(), "a.svg", &keep, &keep).is_err());
+
+        let svg = sanitize(concat!(
+            r#"<?xml version="1.0"?><!DOCTYPE svg [ <!ENTITY % p "x"> ]>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" onload='alert(1)'>"#,
+            r#"<script><![CDATA[alert(2)]]></script>"#,
+            r#"<SET attributeName="href" to="javascript:alert(3)"/>"#,
+            r#"<a href="&#106;avascript:alert(4)">a</a><image xlink:href="pic.png" width="1"/>"#,
+            r#"<style>rect { fill: url(http://x/y) }</style>"#,
+            r#"<text>&undefined;</text><rect/></svg>"#,
+        ));
+        assert!(!svg.contains("alert"), "{}", svg);
+        assert!(!svg.contains("http://x"));
+        assert!(svg.contains("<!DOCTYPE svg>"));
+        assert!(!svg.contains("ENTITY"));
+        assert!(svg.contains(r#"<image xlink:href="pic.png" width="1"/>"#));
+        assert!(svg.contains("<rect/></svg>"));
+    }
+
+    #[test]
+    fn drops_declared_entities_when_stripping_as_text() {
+        let svg = sanitize(concat!(
+            r#"<!DOCTYPE svg [ <!ENTITY x "<script>alert(1)</script>"> ]>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><text>&x;&undefined;</text>"#,
+            r#"<text title="&x;">&lt;a &amp; b&#33; &#x21;</text></svg>"#,
+        ));
+        assert!(!svg.contains("ENTITY"), "{}", svg);
+        assert!(!svg.contains("&x;"));
+        assert!(!svg.contains("&undefined;"));
+        assert!(svg.contains("<!DOCTYPE svg>"));
+        assert!(svg.contains("&lt;a &amp; b&#33; &#x21;"));
+    }
+}
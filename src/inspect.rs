@@ -0,0 +1,358 @@
+// `inspect`: what the converter sees when it opens an ePub.
+//
+// When a book converts badly the question is usually which cover, which
+// spine order or which TOC file the pipeline picked up. This reports the
+// same inputs `process_book` works from, without writing anything.
+
+use epub::doc::{EpubDoc, NavPoint};
+use scraper::{ElementRef, Html, Selector};
+use std::cmp::Reverse;
+
+use super::metadata::{get_metadata, read_package, BookMetadata};
+use super::{count_links, Book};
+
+#[derive(Serialize)]
+pub struct ResourceInfo {
+    pub id: String,
+    pub path: String,
+    pub mime: String,
+    /// `None` when the file listed in the manifest isn't in the archive.
+    pub size: Option<usize>,
+    pub properties: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct SpineEntry {
+    pub position: usize,
+    pub idref: String,
+    pub path: String,
+    pub linear: bool,
+}
+
+#[derive(Serialize, Default)]
+pub struct CoverInfo {
+    /// `<meta name="cover" content="...">`, which is what the converter uses.
+    pub meta_cover: Option<String>,
+    /// The manifest item with `properties="cover-image"` (EPUB3).
+    pub cover_image_item: Option<String>,
+    pub path: Option<String>,
+    pub mime: Option<String>,
+    pub decodes: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TocEntry {
+    pub depth: usize,
+    pub label: String,
+    pub href: String,
+}
+
+#[derive(Serialize)]
+pub struct LinkCount {
+    pub id: String,
+    pub path: String,
+    pub links: usize,
+}
+
+/// The TOC page `process_book` would pick: the HTML file with the most links.
+#[derive(Serialize, Default)]
+pub struct TocHeuristic {
+    pub picked: Option<String>,
+    pub links: usize,
+    /// Files with the same link count as the pick. The converter iterates a
+    /// HashMap, so any of them may win.
+    pub tied: Vec<String>,
+    pub candidates: Vec<LinkCount>,
+}
+
+#[derive(Serialize)]
+pub struct Inspection {
+    pub epub: String,
+    pub opf: String,
+    pub metadata: BookMetadata,
+    pub resources: Vec<ResourceInfo>,
+    pub spine: Vec<SpineEntry>,
+    pub cover: CoverInfo,
+    pub ncx: Vec<TocEntry>,
+    pub nav: Vec<TocEntry>,
+    pub toc_heuristic: TocHeuristic,
+}
+
+fn flatten_navpoints(points: &[NavPoint], depth: usize, entries: &mut Vec<TocEntry>) {
+    for point in points {
+        entries.push(TocEntry {
+            depth,
+            label: point.label.trim().to_string(),
+            href: point.content.to_string_lossy().into_owned(),
+        });
+        flatten_navpoints(&point.children, depth + 1, entries);
+    }
+}
+
+// Entries of the `epub:type="toc"` nav (or the first nav), depth taken
+// from the nesting of `<ol>`s.
+fn nav_entries(html: &str) -> Vec<TocEntry> {
+    let document = Html::parse_document(html);
+    let nav_selector = Selector::parse("nav").unwrap();
+    let link_selector = Selector::parse("a").unwrap();
+    let navs: Vec<ElementRef> = document.select(&nav_selector).collect();
+    let toc = navs
+        .iter()
+        .find(|n| {
+            n.value()
+                .attr("epub:type")
+                .map(|t| t.split_whitespace().any(|t| t == "toc"))
+                .unwrap_or(false)
+        })
+        .or_else(|| navs.first());
+
+    let toc = match toc {
+        Some(toc) => toc,
+        None => return vec![],
+    };
+    toc.select(&link_selector)
+        .map(|a| {
+            let depth = a
+                .ancestors()
+                .filter_map(|n| n.value().as_element())
+                .take_while(|e| e.name() != "nav")
+                .filter(|e| e.name() == "ol" || e.name() == "ul")
+                .count();
+            TocEntry {
+                depth: depth.saturating_sub(1),
+                label: a.text().collect::<String>().trim().to_string(),
+                href: a.value().attr("href").unwrap_or_default().to_string(),
+            }
+        })
+        .collect()
+}
+
+pub fn inspect(epub: &str) -> Result<Inspection, String> {
+    let mut doc = EpubDoc::new(epub).map_err(|e| format!("Can't open {}: {}", epub, e))?;
+    let package = read_package(&mut doc);
+    let book = Book {
+        epub: epub.to_string(),
+        ..Default::default()
+    };
+    let metadata = get_metadata(&book);
+
+    let properties_of = |id: &str| {
+        package
+            .manifest
+            .iter()
+            .find(|item| item.id == id)
+            .map(|item| item.properties.clone())
+            .unwrap_or_default()
+    };
+
+    let mut ids: Vec<String> = doc.resources.keys().cloned().collect();
+    ids.sort();
+    let mut resources = vec![];
+    for id in ids.iter() {
+        let (path, mime) = doc.resources[id].clone();
+        resources.push(ResourceInfo {
+            id: id.clone(),
+            path: path.to_string_lossy().into_owned(),
+            mime,
+            size: doc.get_resource(id).ok().map(|data| data.len()),
+            properties: properties_of(id),
+        });
+    }
+
+    let spine = package
+        .spine
+        .iter()
+        .enumerate()
+        .map(|(i, item)| SpineEntry {
+            position: i + 1,
+            idref: item.idref.clone(),
+            path: doc
+                .resources
+                .get(&item.idref)
+                .map(|r| r.0.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            linear: item.linear,
+        })
+        .collect();
+
+    let mut cover = CoverInfo {
+        meta_cover: doc.get_cover_id().ok(),
+        cover_image_item: package
+            .manifest
+            .iter()
+            .find(|item| item.properties.iter().any(|p| p == "cover-image"))
+            .map(|item| item.id.clone()),
+        ..Default::default()
+    };
+    match &cover.meta_cover {
+        Some(id) => match doc.resources.get(id) {
+            Some((path, mime)) => {
+                cover.path = Some(path.to_string_lossy().into_owned());
+                cover.mime = Some(mime.clone());
+                match doc.get_cover() {
+                    Ok(data) => match image::load_from_memory(&data) {
+                        Ok(_) => cover.decodes = true,
+                        Err(e) => cover.error = Some(format!("can't decode cover: {}", e)),
+                    },
+                    Err(e) => cover.error = Some(format!("can't read cover: {}", e)),
+                }
+            }
+            None => cover.error = Some(format!("cover id {} is not in the manifest", id)),
+        },
+        None => {
+            cover.error = Some("no <meta name=\"cover\">, the book will have no cover".to_string())
+        }
+    }
+
+    let mut ncx = vec![];
+    flatten_navpoints(&doc.toc, 0, &mut ncx);
+
+    let nav = match package
+        .manifest
+        .iter()
+        .find(|item| item.properties.iter().any(|p| p == "nav"))
+    {
+        Some(item) => doc
+            .get_resource_str(&item.id)
+            .map(|html| nav_entries(&html))
+            .unwrap_or_default(),
+        None => vec![],
+    };
+
+    let mut candidates = vec![];
+    for resource in resources.iter().filter(|r| r.mime.contains("html")) {
+        if let Ok(html) = doc.get_resource_str(&resource.id) {
+            candidates.push(LinkCount {
+                id: resource.id.clone(),
+                path: resource.path.clone(),
+                links: count_links(&html),
+            });
+        }
+    }
+    candidates.sort_by_key(|c| Reverse(c.links));
+    let mut toc_heuristic = TocHeuristic::default();
+    if let Some(best) = candidates.first().filter(|c| c.links > 0) {
+        toc_heuristic.picked = Some(best.id.clone());
+        toc_heuristic.links = best.links;
+        toc_heuristic.tied = candidates
+            .iter()
+            .skip(1)
+            .filter(|c| c.links == best.links)
+            .map(|c| c.id.clone())
+            .collect();
+    }
+    toc_heuristic.candidates = candidates;
+
+    Ok(Inspection {
+        epub: epub.to_string(),
+        opf: doc.root_file.to_string_lossy().into_owned(),
+        metadata,
+        resources,
+        spine,
+        cover,
+        ncx,
+        nav,
+        toc_heuristic,
+    })
+}
+
+fn print_toc(title: &str, entries: &[TocEntry]) {
+    println!("\n{} ({} entries)", title, entries.len());
+    for entry in entries {
+        println!(
+            "  {}{}  ->  {}",
+            "  ".repeat(entry.depth),
+            entry.label,
+            entry.href
+        );
+    }
+}
+
+/// Prints an inspection as plain text tables.
+pub fn print_table(inspection: &Inspection) {
+    let metadata = &inspection.metadata;
+    println!("ePub:       {}", inspection.epub);
+    println!("OPF:        {}", inspection.opf);
+    println!("Title:      {}", metadata.title);
+    println!("Author:     {}", metadata.author);
+    println!("Date:       {}", metadata.date);
+    println!("Language:   {} ({})", metadata.language, metadata.direction);
+    println!("Identifier: {}", metadata.unique_identifier);
+    println!("Publisher:  {}", metadata.publisher);
+    if let Some(series) = &metadata.series {
+        println!("Series:     {} #{}", series.name, series.position);
+    }
+
+    println!("\nManifest ({} resources)", inspection.resources.len());
+    println!("  {:<20} {:<28} {:>9}  path", "id", "mime", "bytes");
+    for r in inspection.resources.iter() {
+        let size = r
+            .size
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "MISSING".to_string());
+        let properties = if r.properties.is_empty() {
+            String::new()
+        } else {
+            format!("  [{}]", r.properties.join(" "))
+        };
+        println!(
+            "  {:<20} {:<28} {:>9}  {}{}",
+            r.id, r.mime, size, r.path, properties
+        );
+    }
+
+    println!("\nSpine ({} items)", inspection.spine.len());
+    for s in inspection.spine.iter() {
+        println!(
+            "  {:>3}. {:<20} {}{}",
+            s.position,
+            s.idref,
+            if s.path.is_empty() {
+                "NOT IN MANIFEST"
+            } else {
+                &s.path
+            },
+            if s.linear { "" } else { "  (linear=no)" }
+        );
+    }
+
+    let cover = &inspection.cover;
+    println!("\nCover");
+    println!(
+        "  meta cover:       {}",
+        cover.meta_cover.as_deref().unwrap_or("-")
+    );
+    println!(
+        "  cover-image item: {}",
+        cover.cover_image_item.as_deref().unwrap_or("-")
+    );
+    println!(
+        "  path:             {}",
+        cover.path.as_deref().unwrap_or("-")
+    );
+    println!(
+        "  mime:             {}",
+        cover.mime.as_deref().unwrap_or("-")
+    );
+    println!("  decodes:          {}", cover.decodes);
+    if let Some(error) = &cover.error {
+        println!("  problem:          {}", error);
+    }
+
+    print_toc("NCX TOC", &inspection.ncx);
+    print_toc("Nav TOC", &inspection.nav);
+
+    let heuristic = &inspection.toc_heuristic;
+    println!("\nTOC page by link count");
+    match &heuristic.picked {
+        Some(id) => println!("  picked: {} ({} links)", id, heuristic.links),
+        None => println!("  picked: none, toc.html will be a copy of the cover"),
+    }
+    if !heuristic.tied.is_empty() {
+        println!("  tied with: {}", heuristic.tied.join(", "));
+    }
+    for c in heuristic.candidates.iter().take(5) {
+        println!("  {:>5}  {}", c.links, c.path);
+    }
+}
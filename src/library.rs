@@ -0,0 +1,151 @@
+// Library landing page for a batch job.
+//
+// When a batch JSON carries a `library` section, every book marked as
+// `success` is listed on a single index page with its own manifest and
+// service worker, so the whole collection installs as one PWA.
+
+use fs_extra::dir::get_dir_content;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tera::Context;
+
+use super::media_overlay;
+use super::metadata::{get_metadata, BookMetadata};
+use super::templates::ResourceDirs;
+use super::{move_service_worker, Book};
+use tera::Tera;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct LibraryConfig {
+    pub output_folder: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub base_url: String,
+}
+
+#[derive(Serialize)]
+struct LibraryEntry {
+    title: String,
+    author: String,
+    description: String,
+    language: String,
+    base_url: String,
+    info_url: String,
+    cover: Option<String>,
+    metadata: BookMetadata,
+}
+
+#[derive(Serialize)]
+struct LibraryGroup {
+    language: String,
+    books: Vec<LibraryEntry>,
+}
+
+fn library_entry(book: &Book) -> LibraryEntry {
+    let metadata = get_metadata(book);
+    let has_cover = Path::new(&book.output_folder)
+        .join("cover_resized.jpg")
+        .exists();
+
+    LibraryEntry {
+        title: metadata.title.clone(),
+        author: metadata.author.clone(),
+        description: metadata.description.clone(),
+        language: metadata.language.clone(),
+        base_url: book.base_url.clone(),
+        info_url: book.info_url.clone(),
+        cover: if has_cover {
+            Some(format!("{}/cover_resized.jpg", book.base_url))
+        } else {
+            None
+        },
+        metadata,
+    }
+}
+
+fn write_rendered(tera: &Tera, template: &str, ctx: &Context, destination: &Path) {
+    let rendered = tera
+        .render(template, ctx)
+        .expect("Failed to render library template");
+    let f = fs::File::create(destination);
+    assert!(f.is_ok());
+    let mut f = f.unwrap();
+    let _resp = f.write_all(rendered.as_bytes());
+}
+
+/// Renders the library index, manifest and service worker for every
+/// successfully converted book in `books`, returning the files written.
+pub fn process_library(
+    config: &LibraryConfig,
+    books: &[Book],
+    dirs: &ResourceDirs,
+) -> Vec<PathBuf> {
+    let output_root = Path::new(&config.output_folder);
+    let _resp = fs::create_dir_all(output_root.join("resources"));
+
+    info!("Building library index in {}", &config.output_folder);
+
+    let mut groups: BTreeMap<String, Vec<LibraryEntry>> = BTreeMap::new();
+    let mut total = 0;
+    for book in books.iter().filter(|b| b.status == "success") {
+        if !Path::new(&book.epub).exists() {
+            warn!("Skipping {}, epub is missing", &book.epub);
+            continue;
+        }
+        let entry = library_entry(book);
+        groups
+            .entry(entry.language.clone())
+            .or_default()
+            .push(entry);
+        total += 1;
+    }
+
+    let groups: Vec<LibraryGroup> = groups
+        .into_iter()
+        .map(|(language, mut books)| {
+            books.sort_by_key(|b| b.title.to_lowercase());
+            LibraryGroup { language, books }
+        })
+        .collect();
+    let languages: Vec<&String> = groups.iter().map(|g| &g.language).collect();
+
+    let mut ctx = Context::new();
+    ctx.insert("title", &config.title);
+    ctx.insert("description", &config.description);
+    ctx.insert("base_url", &config.base_url);
+    ctx.insert("groups", &groups);
+    ctx.insert("languages", &languages);
+    ctx.insert("total", &total);
+
+    let tera = dirs.tera();
+    dirs.copy_static(output_root);
+    write_rendered(&tera, "library.html", &ctx, &output_root.join("index.html"));
+    write_rendered(
+        &tera,
+        "library.webmanifest",
+        &ctx,
+        &output_root.join("manifest.webmanifest"),
+    );
+    move_service_worker(output_root);
+    // sw.js imports it, the library has nothing to precache
+    media_overlay::write_precache_manifest(output_root, &BTreeSet::new());
+
+    info!("Library lists {} books", total);
+
+    let written = [
+        "index.html",
+        "manifest.webmanifest",
+        "sw.js",
+        "precache-manifest.js",
+    ];
+    let mut files: Vec<PathBuf> = written.iter().map(|name| output_root.join(name)).collect();
+    if let Ok(content) = get_dir_content(output_root.join("resources").join("static")) {
+        files.extend(content.files.iter().map(PathBuf::from));
+    }
+    files
+}
@@ -0,0 +1,56 @@
+// Console logging for the command line.
+//
+// Progress goes to stdout at `info`, problems go to stderr. `--quiet` only
+// keeps errors, each `-v` shows one more level of detail.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io::{self, Write};
+
+struct ConsoleLogger {
+    level: LevelFilter,
+}
+
+impl Log for ConsoleLogger {
+    // Dependencies such as html5ever log their parser states at trace
+    // level, so only their warnings and errors get through.
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let ours = metadata
+            .target()
+            .starts_with(module_path!().split("::").next().unwrap());
+        metadata.level() <= self.level && (ours || metadata.level() <= Level::Warn)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let _resp = match record.level() {
+            Level::Error => writeln!(io::stderr(), "error: {}", record.args()),
+            Level::Warn => writeln!(io::stderr(), "warning: {}", record.args()),
+            Level::Info => writeln!(io::stdout(), "{}", record.args()),
+            Level::Debug | Level::Trace => writeln!(io::stdout(), "  {}", record.args()),
+        };
+    }
+
+    fn flush(&self) {
+        let _resp = io::stdout().flush();
+    }
+}
+
+/// The level for `-v` given `verbosity` times, or `Error` with `--quiet`.
+pub fn level_for(verbosity: u64, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    match verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+pub fn init(level: LevelFilter) {
+    let logger: &'static ConsoleLogger = Box::leak(Box::new(ConsoleLogger { level }));
+    log::set_logger(logger).expect("Logger already set");
+    log::set_max_level(level);
+}
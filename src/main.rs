@@ -5,40 +5,81 @@ add timestamp to all HTML includes and the respective file names
 extern crate epub;
 extern crate image;
 extern crate scraper;
-#[macro_use]
 extern crate tera;
-#[macro_use]
-extern crate lazy_static;
 extern crate fs_extra;
 #[macro_use]
 extern crate clap;
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate csv;
+extern crate chrono;
+#[macro_use]
+extern crate log;
+extern crate sha1_smol;
+extern crate subsetter;
+extern crate ttf_parser;
+extern crate brotli;
+extern crate flate2;
+extern crate lightningcss;
+extern crate minify_html;
+extern crate minify_js;
+extern crate xml;
+extern crate zip;
+
+mod inspect;
+mod accessibility;
+mod compress;
+mod css;
+mod fixed_layout;
+mod fonts;
+mod images;
+mod library;
+mod logging;
+mod media_overlay;
+mod metadata;
+mod minify;
+mod notes;
+mod opds;
+mod preflight;
+mod serve;
+mod structured_data;
+mod templates;
+mod theme;
+mod validate;
+mod woff2;
+mod xhtml;
 
 use epub::doc::EpubDoc;
-use fs_extra::dir::*;
+use metadata::{get_metadata, metadata_context, text_direction, BookMetadata};
 use image::imageops;
 use image::imageops::FilterType;
 use scraper::{Html, Selector};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
-use std::io::{self, Write};
+use clap::ArgMatches;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
 use std::path::Path;
-use std::path::PathBuf;
-use tera::{Context, Tera};
+use templates::ResourceDirs;
+use tera::Tera;
 
 const MAX_WIDTH: u32 = 600;
 const MAX_HEIGHT: u32 = 900;
 const COVER_WIDTH: u32 = 700;
 const ICON_WIDTH: u32 = 192;
-static DEFAULT_OUTPUT_FOLDER: &'static str = "web/";
+static DEFAULT_OUTPUT_FOLDER: &str = "web/";
 
-#[derive(Serialize, Deserialize, Clone)]
+const EXIT_OK: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 struct Book {
     info_url: String,
     base_url: String,
@@ -47,12 +88,60 @@ struct Book {
     output_folder: String,
     status: String,
     error: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    epub_url: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    language: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    theme_color: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    background_color: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    accent_color: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    templates: String,
+    #[serde(default, rename = "static", skip_serializing_if = "String::is_empty")]
+    static_dir: String,
+    /// Links the book CSS as is instead of scoping it to `.book-content`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    unscoped_css: bool,
+    /// Minifies the pages, book CSS and static files.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    minify: bool,
+    /// Writes `.gz` and `.br` copies of the text files.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    precompress: bool,
+    /// Converts GIFs to animated WebP where that comes out smaller.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    webp_gifs: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct BatchJob {
     report: BatchJobReport,
     books: Vec<Book>,
+    #[serde(default)]
+    templates: String,
+    #[serde(default, rename = "static", skip_serializing_if = "String::is_empty")]
+    static_dir: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    library: Option<library::LibraryConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    opds: Option<opds::OpdsConfig>,
+}
+
+/// What every chapter needs to know about the rest of the book.
+struct BookIndex {
+    /// Output path of each resource, keyed by its path in the ePub.
+    outputs: HashMap<String, String>,
+    notes: notes::NotesMap,
+    encryption: fonts::Encryption,
+    /// Whether book CSS is scoped to `.book-content`.
+    scoped_css: bool,
+    /// Fixed-layout pages keyed by manifest id.
+    pages: HashMap<String, fixed_layout::FixedPage>,
+    /// Media overlay JSON of narrated chapters keyed by manifest id.
+    overlays: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -61,15 +150,32 @@ struct BatchJobReport {
     skipped: u32,
     error: u32,
     elapsed_time: String,
+    /// Preflight issues of each converted book, keyed by epub path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    preflight: BTreeMap<String, preflight::PreflightReport>,
+    /// Size savings of each minified book, keyed by epub path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    minified: BTreeMap<String, minify::MinifyReport>,
+    /// Precompressed files of each book, keyed by epub path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    precompressed: BTreeMap<String, compress::PrecompressReport>,
+    /// Accessibility score of each converted book, keyed by epub path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    accessibility: BTreeMap<String, accessibility::AccessibilityScore>,
+    /// Precompressed files of the library index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    library_precompressed: Option<compress::PrecompressReport>,
+    /// Precompressed files of the OPDS feeds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    opds_precompressed: Option<compress::PrecompressReport>,
 }
 
-lazy_static! {
-    pub static ref TERA: Tera = {
-        let mut tera = compile_templates!("templates/**/*");
-        // and we can add more things to our instance if we want to
-        tera.autoescape_on(vec!["html"]);
-        tera
-    };
+/// What `process_book` did besides converting, for the batch report.
+#[derive(Default)]
+struct BookReport {
+    minified: Option<minify::MinifyReport>,
+    precompressed: Option<compress::PrecompressReport>,
+    accessibility: Option<accessibility::AccessibilityScore>,
 }
 
 fn replace_if(s: String, from: &str, to: &str) -> String {
@@ -80,13 +186,98 @@ fn replace_if(s: String, from: &str, to: &str) -> String {
     }
 }
 
+/// Number of `<a>` elements in a chapter. The chapter with the most links
+/// is taken to be the table of contents.
+fn count_links(html: &str) -> usize {
+    let link_selector = Selector::parse("a").unwrap();
+    Html::parse_document(html).select(&link_selector).count()
+}
+
+// Chapters are written flat into the output folder, `.xhtml` renamed to
+// `.html`, whatever folder they were in inside the ePub.
 fn extract_filename(path: &Path) -> String {
-    
-    let mut path2 = PathBuf::from(path);
-    path2.set_extension(".html");
-    
-    return path2.into_os_string().into_string().unwrap();
-    
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or_default()
+        .replace(".xhtml", ".html")
+}
+
+// Image and chapter links as they are in the output folder. The `.xhtml`
+// rename is needed because some epubs have broken XHTML inside them.
+fn fix_chapter_links(html: &str) -> String {
+    flatten_chapter_links(&html.replace("../images", "images").replace(".xhtml", ".html"))
+}
+
+// Points relative links to chapters at the flattened file names, so
+// `text/ch1.html#s2` becomes `ch1.html#s2`.
+fn flatten_chapter_links(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("href=\"") {
+        let value_start = start + "href=\"".len();
+        let value_end = match rest[value_start..].find('"') {
+            Some(end) => value_start + end,
+            None => break,
+        };
+        let link = &rest[value_start..value_end];
+        let path = link.split('#').next().unwrap_or_default();
+        result.push_str(&rest[..value_start]);
+        let relative = !path.contains(':') && !path.starts_with('/');
+        if relative && path.contains('/') && path.ends_with(".html") {
+            result.push_str(&link[path.rfind('/').unwrap() + 1..]);
+        } else {
+            result.push_str(link);
+        }
+        rest = &rest[value_end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+// Points image references at `images/`, where images are written under
+// their manifest id and GIFs may have become WebP. Runs on the chapter as
+// it is in the ePub, before `fix_chapter_links`.
+fn fix_image_links(html: &str, path: &str, outputs: &HashMap<String, String>) -> String {
+    let path = css::percent_decode(path);
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("=\"") {
+        let value_start = start + "=\"".len();
+        let value_end = match rest[value_start..].find('"') {
+            Some(end) => value_start + end,
+            None => break,
+        };
+        let attribute = rest[..start]
+            .rsplit(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or_default();
+        let link = &rest[value_start..value_end];
+        let (file, suffix) = match link.find(['?', '#']) {
+            Some(i) => link.split_at(i),
+            None => (link, ""),
+        };
+        let output = if ["src", "href", "xlink:href", "poster"].contains(&attribute)
+            && !file.is_empty()
+            && !file.contains(':')
+        {
+            outputs
+                .get(&css::resolve_path(&path, &css::percent_decode(file)))
+                .filter(|output| output.starts_with("images/"))
+        } else {
+            None
+        };
+        result.push_str(&rest[..value_start]);
+        match output {
+            Some(output) => {
+                result.push_str(output);
+                result.push_str(suffix);
+            }
+            None => result.push_str(link),
+        }
+        rest = &rest[value_end..];
+    }
+    result.push_str(rest);
+    result
 }
 
 fn copy_index_to_cover(output_root: &Path) {
@@ -105,52 +296,26 @@ fn move_service_worker(output_root: &Path) {
     .expect("Can't create sw.js");
 }
 
-fn get_metadata(book: &Book) -> HashMap<&str, String> {
-    let input_file = &book.epub;
-    let doc = EpubDoc::new(input_file).unwrap();
-    let mut metadata = HashMap::new();
-    let title = doc.mdata("title").unwrap_or_default();
-    let author = doc.mdata("creator").unwrap_or_default();
-    let date = doc.mdata("date").unwrap_or_default();
-    let description = &book.description;
-    let base_url = &book.base_url;
-    let info_url = &book.info_url;
-
-    metadata.insert("title", title.clone());
-    metadata.insert("author", author.clone());
-    metadata.insert("date", date.clone());
-    metadata.insert("description", description.clone());
-    metadata.insert("info_url", info_url.clone());
-    metadata.insert("base_url", base_url.clone());
-
-    return metadata;
-}
-
-fn compress_cover(book: &Book) {
+fn compress_cover(book: &Book, metadata: &BookMetadata, tera: &Tera, minifier: &minify::Minifier) {
     let input_file = &book.epub;
     let output_root = Path::new(&book.output_folder);
     let doc = EpubDoc::new(input_file);
     assert!(doc.is_ok());
     let mut doc = doc.unwrap();
-    println!("Extracting cover...");
+    debug!("Extracting cover...");
     match doc.get_cover_id() {
         Ok(cover_id) => {
             let cover_mime = doc
                 .get_resource_mime(&cover_id)
                 .expect("Can't get cover mime");
-            println!("Cover mime: {}", &cover_mime);
+            debug!("Cover mime: {}", &cover_mime);
             let cover_data = doc.get_cover();
 
             match cover_data {
                 Err(error) => {
-                    println!("this book has a broken cover: {}", &error);
+                    warn!("{} has a broken cover: {}", input_file, &error);
                     // create cover html ...
-                    let metadata = get_metadata(&book);
-
-                    let mut ctx = Context::new();
-                    for (key, val) in metadata.into_iter() {
-                        ctx.insert(key, &val);
-                    }
+                    let mut ctx = metadata_context(metadata);
 
                     let mut chapter = HashMap::new();
                     chapter.insert("title", "Table of Contents");
@@ -170,14 +335,15 @@ fn compress_cover(book: &Book) {
                         None => ctx.insert("next", &false),
                     }
 
-                    let rendered = TERA
+                    let rendered = tera
                         .render("index.html", &ctx)
                         .expect("Failed to render template");
+                    let rendered = minifier.html("index.html", rendered);
 
                     let f = fs::File::create(output_root.join("index.html"));
                     assert!(f.is_ok());
                     let mut f = f.unwrap();
-                    let _resp = f.write_all(&rendered.as_bytes());
+                    let _resp = f.write_all(rendered.as_bytes());
                 }
                 Ok(data) => {
                     let tempfile = match cover_mime.as_ref() {
@@ -185,24 +351,26 @@ fn compress_cover(book: &Book) {
                         "image/jpg" | "image/jpeg" => "temp/cover.jpg",
                         _ => "temp/cover.jpg",
                     };
-                    let f = fs::File::create(&tempfile);
+                    let f = fs::File::create(tempfile);
                     assert!(f.is_ok());
                     let mut f = f.unwrap();
                     let _resp = f.write_all(&data);
-                    println!("Compressing cover...");
+                    debug!("Compressing cover...");
 
-                    let img = image::open(&tempfile).unwrap();
+                    let img = image::open(tempfile).unwrap();
                     let resized = img.resize(COVER_WIDTH, COVER_WIDTH, FilterType::Lanczos3);
                     resized
                         .save(output_root.join("cover.jpg"))
                         .expect("Saving image failed");
 
-                    let ref mut background = image::RgbaImage::new(ICON_WIDTH, ICON_WIDTH);
+                    let background = &mut image::RgbaImage::new(ICON_WIDTH, ICON_WIDTH);
+                    let [r, g, b] = theme::parse_hex(&metadata.theme.background_color)
+                        .unwrap_or([33, 33, 33]);
                     for (_x, _y, pixel) in background.enumerate_pixels_mut() {
-                        *pixel = image::Rgba([33, 33, 33, 0]);
+                        *pixel = image::Rgba([r, g, b, 255]);
                     }
 
-                    let img = image::open(&tempfile).unwrap();
+                    let img = image::open(tempfile).unwrap();
                     let resized_icon = img.resize(ICON_WIDTH, ICON_WIDTH, FilterType::Lanczos3);
                     resized_icon
                         .save(output_root.join("cover_resized.jpg"))
@@ -220,12 +388,7 @@ fn compress_cover(book: &Book) {
                         .expect("Saving icon failed");
 
                     // create cover html ...
-                    let metadata = get_metadata(&book);
-
-                    let mut ctx = Context::new();
-                    for (key, val) in metadata.into_iter() {
-                        ctx.insert(key, &val);
-                    }
+                    let mut ctx = metadata_context(metadata);
 
                     let mut chapter = HashMap::new();
                     chapter.insert("title", "Table of Contents");
@@ -233,16 +396,9 @@ fn compress_cover(book: &Book) {
 
                     ctx.insert("chapter", &chapter);
 
-                    println!("spine len: {}", &doc.spine.len());
-                    // for k in doc.spine.iter() {
-                    //     println!("spine {}", &k);
-                    // }
+                    debug!("spine len: {}", &doc.spine.len());
 
-                    let next_chapter_id = if doc.spine.len() > 2 {
-                        &doc.spine[1]
-                    } else {
-                        &doc.spine[1]
-                    };
+                    let next_chapter_id = &doc.spine[1];
                     let next_chapter = &doc.resources.get(next_chapter_id);
 
                     match next_chapter {
@@ -250,26 +406,22 @@ fn compress_cover(book: &Book) {
                         None => ctx.insert("next", &false),
                     }
 
-                    let rendered = TERA
+                    let rendered = tera
                         .render("index.html", &ctx)
                         .expect("Failed to render template");
+                    let rendered = minifier.html("index.html", rendered);
 
                     let f = fs::File::create(output_root.join("index.html"));
                     assert!(f.is_ok());
                     let mut f = f.unwrap();
-                    let _resp = f.write_all(&rendered.as_bytes());
+                    let _resp = f.write_all(rendered.as_bytes());
                 }
             }
         }
         Err(e) => {
-            println!("This book has no cover: {}", e);
+            warn!("{} has no cover: {}", input_file, e);
             // create cover html ...
-            let metadata = get_metadata(&book);
-
-            let mut ctx = Context::new();
-            for (key, val) in metadata.into_iter() {
-                ctx.insert(key, &val);
-            }
+            let mut ctx = metadata_context(metadata);
 
             let mut chapter = HashMap::new();
             chapter.insert("title", "Table of Contents");
@@ -289,64 +441,257 @@ fn compress_cover(book: &Book) {
                 None => ctx.insert("next", &false),
             }
 
-            let rendered = TERA
+            let rendered = tera
                 .render("index.html", &ctx)
                 .expect("Failed to render template");
+            let rendered = minifier.html("index.html", rendered);
 
             let f = fs::File::create(output_root.join("index.html"));
             assert!(f.is_ok());
             let mut f = f.unwrap();
-            let _resp = f.write_all(&rendered.as_bytes());
+            let _resp = f.write_all(rendered.as_bytes());
         }
     }
 }
 
-fn copy_raw_resource(input_file: &str, key: &str, path: &str, output_root: &Path) {
+fn copy_raw_resource(
+    input_file: &str,
+    key: &str,
+    path: &str,
+    output_root: &Path,
+    encryption: &fonts::Encryption,
+) {
     let doc = EpubDoc::new(input_file);
     assert!(doc.is_ok());
     let mut doc = doc.unwrap();
-    let filename = Path::new(path)
-        .file_name()
-        .and_then(OsStr::to_str)
-        .unwrap_or_default();
+    let filename = css::percent_decode(
+        Path::new(path)
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default(),
+    );
 
-    let data = doc.get_resource(key);
+    let mut data = doc.get_resource(key).expect("Can't read resource");
+    encryption.decode(path, &mut data);
 
     // write raw file
-    let raw_filename = output_root.join("resources").join(&filename);
+    let raw_filename = output_root.join("resources").join(filename);
     let f = fs::File::create(&raw_filename);
     assert!(f.is_ok());
     let mut f = f.unwrap();
-    let _resp = f.write_all(&data.unwrap().as_slice());
+    let _resp = f.write_all(data.as_slice());
+}
+
+/// Where a resource from the ePub ends up, relative to the output folder.
+/// Has to agree with what `compress_image_resource`, `copy_image_resource`,
+/// `process_svg_resource`, `process_html_resource`, `process_css_resource`
+/// and `copy_raw_resource` write. Fonts converted to WOFF2 and GIFs
+/// converted to WebP are added by `process_book`.
+fn output_path(key: &str, path: &Path, mime: &str) -> String {
+    let filename =
+        css::percent_decode(path.file_name().and_then(OsStr::to_str).unwrap_or_default());
+    let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+    if mime.contains("image/") {
+        format!("images/{}.{}", key, ext)
+    } else if mime.contains("html") {
+        extract_filename(path)
+    } else {
+        format!("resources/{}", filename)
+    }
+}
+
+/// Output paths of all resources, keyed by their decoded path in the ePub.
+fn output_paths(doc: &EpubDoc<::std::io::BufReader<File>>) -> HashMap<String, String> {
+    doc.resources
+        .iter()
+        .map(|(key, (path, mime))| {
+            (
+                css::percent_decode(&path.to_string_lossy()),
+                output_path(key, path, mime),
+            )
+        })
+        .collect()
+}
+
+/// The book stylesheets a chapter links to, as output paths.
+fn chapter_stylesheets(
+    document: &Html,
+    path: &str,
+    outputs: &HashMap<String, String>,
+) -> Vec<String> {
+    let selector = Selector::parse("link[href]").unwrap();
+    document
+        .select(&selector)
+        .filter(|link| {
+            link.value()
+                .attr("rel")
+                .map(|rel| {
+                    rel.split_whitespace()
+                        .any(|r| r.eq_ignore_ascii_case("stylesheet"))
+                })
+                .unwrap_or(false)
+        })
+        .filter_map(|link| {
+            let href = link.value().attr("href").unwrap_or_default();
+            let target =
+                css::resolve_path(&css::percent_decode(path), &css::percent_decode(href));
+            let output = outputs.get(&target).cloned();
+            if output.is_none() {
+                warn!("{} links to missing stylesheet {}", path, href);
+            }
+            output
+        })
+        .collect()
+}
+
+/// What the re-templated page keeps of the chapter's own `<html>`, `<head>`
+/// and `<body>`: body class, id and `epub:type`, language and the head
+/// `<style>` blocks, which go through the same rewriting as book CSS.
+/// `source` is the chapter as it is in the ePub, so style references are
+/// still relative to `path`.
+fn chapter_context(
+    source: &str,
+    path: &str,
+    index: &BookIndex,
+) -> HashMap<&'static str, String> {
+    let mut chapter = HashMap::new();
+    let document = Html::parse_document(source);
+    let body_selector = Selector::parse("body").unwrap();
+    let html_selector = Selector::parse("html").unwrap();
+    let body = document.select(&body_selector).next();
+    let html = document.select(&html_selector).next();
+
+    if let Some(body) = body {
+        let attributes = [
+            ("class", "body_class"),
+            ("id", "body_id"),
+            ("epub:type", "epub_type"),
+        ];
+        for (attribute, name) in attributes.iter() {
+            if let Some(value) = body.value().attr(attribute) {
+                let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+                if !value.is_empty() {
+                    chapter.insert(*name, value);
+                }
+            }
+        }
+    }
+
+    // chapters in a different language than the book carry their own xml:lang
+    let lang = [body, html]
+        .iter()
+        .flatten()
+        .filter_map(|e| e.value().attr("xml:lang").or_else(|| e.value().attr("lang")))
+        .map(str::trim)
+        .find(|l| !l.is_empty());
+    if let Some(lang) = lang {
+        chapter.insert("lang", lang.to_string());
+    }
+
+    let style_selector = Selector::parse("head style").unwrap();
+    let styles = document
+        .select(&style_selector)
+        .map(|style| style.text().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !styles.trim().is_empty() {
+        // the page is at the output root, like every output path
+        let styles = css::rewrite_urls(&styles, |reference| {
+            let target =
+                css::resolve_path(&css::percent_decode(path), &css::percent_decode(reference));
+            let output = index.outputs.get(&target).cloned();
+            if output.is_none() {
+                warn!("{} references missing file {}", path, reference);
+            }
+            output
+        });
+        let styles = css::set_woff2_formats(&styles);
+        let styles = if index.scoped_css {
+            css::scope_rules(&styles, ".book-content")
+        } else {
+            styles
+        };
+        chapter.insert("styles", styles.replace("</style", "<\\/style"));
+    }
+    chapter
 }
 
-fn process_css_resource(input_file: &str, key: &str, path: &str, output_root: &Path) {
+fn process_css_resource(
+    input_file: &str,
+    key: &str,
+    path: &str,
+    output_root: &Path,
+    outputs: &HashMap<String, String>,
+    scoped: bool,
+    minifier: &minify::Minifier,
+) {
     let doc = EpubDoc::new(input_file);
     assert!(doc.is_ok());
     let mut doc = doc.unwrap();
-    let filename = Path::new(path)
-        .file_name()
-        .and_then(OsStr::to_str)
-        .unwrap_or_default();
+    let filename = css::percent_decode(
+        Path::new(path)
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default(),
+    );
+    let css_output = format!("resources/{}", filename);
 
     //  write fragment
     let str_data = doc.get_resource_str(key);
 
-    let fixed_content = str_data.unwrap().replace("../fonts/", "");
+    // point url() and @import at where things are in the output, copying
+    // files that are in the archive but missing from the manifest
+    let fixed_content = css::rewrite_urls(&str_data.unwrap(), |reference| {
+        let target =
+            css::resolve_path(&css::percent_decode(path), &css::percent_decode(reference));
+        if let Some(output) = outputs.get(&target) {
+            return Some(css::relative_path(&css_output, output));
+        }
+        match doc.get_resource_by_path(&target) {
+            Ok(data) => {
+                let name = Path::new(&target)
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .unwrap_or_default()
+                    .to_string();
+                debug!("copying unlisted {} for {}", &target, path);
+                fs::write(output_root.join("resources").join(&name), data)
+                    .expect("Can't write stylesheet asset");
+                Some(css::relative_path(&css_output, &format!("resources/{}", name)))
+            }
+            Err(_) => {
+                warn!("{} references missing file {}", path, reference);
+                None
+            }
+        }
+    });
+
+    // fonts converted by `fonts::convert_fonts` are WOFF2 now
+    let fixed_content = css::set_woff2_formats(&fixed_content);
 
-    let full_path = output_root.join("resources").join(&filename);
+    // keep book selectors like `body` or `h1` off the reader header
+    let fixed_content = if scoped {
+        css::scope_rules(&fixed_content, ".book-content")
+    } else {
+        fixed_content
+    };
+    let fixed_content = minifier.css(&css_output, fixed_content);
+
+    let full_path = output_root.join("resources").join(filename);
     let f = fs::File::create(&full_path);
     assert!(f.is_ok());
     let mut f = f.unwrap();
-    let _resp = f.write_all(&fixed_content.as_bytes());
+    let _resp = f.write_all(fixed_content.as_bytes());
 }
-fn process_manifest(_input_file: &str, metadata: &HashMap<&str, String>, output_root: &Path) {
-    let mut ctx = Context::new();
-    for (key, val) in metadata.into_iter() {
-        ctx.insert(key, &val);
-    }
+fn process_manifest(
+    _input_file: &str,
+    metadata: &BookMetadata,
+    output_root: &Path,
+    tera: &Tera,
+) {
+    let ctx = metadata_context(metadata);
 
-    let rendered = TERA
+    let rendered = tera
         .render("manifest.webmanifest", &ctx)
         .expect("Failed to render manifest");
 
@@ -354,85 +699,117 @@ fn process_manifest(_input_file: &str, metadata: &HashMap<&str, String>, output_
     let f = fs::File::create(&fragment_filename);
     assert!(f.is_ok());
     let mut f = f.unwrap();
-    let _resp = f.write_all(&rendered.as_bytes());
+    let _resp = f.write_all(rendered.as_bytes());
+}
+
+fn process_metadata_json(metadata: &BookMetadata, output_root: &Path) {
+    let j = serde_json::to_string_pretty(metadata).expect("Can't serialize metadata");
+    fs::write(output_root.join("metadata.json"), &j).expect("Can't write metadata.json");
+}
+
+// A chapter as its pages show it: read as HTML, with its images and
+// chapter links pointed at the output. Chapter pages and the TOC page,
+// which is usually a chapter too, both go through here.
+fn convert_chapter(source: &str, path: &str, outputs: &HashMap<String, String>) -> String {
+    let source = xhtml::to_html(source, path).unwrap_or_else(|| source.to_string());
+    fix_chapter_links(&fix_image_links(&source, path, outputs))
+}
+
+// The body of a converted chapter with its noterefs given popovers.
+// `filename` is the chapter's own page, the one `#` note links are in.
+fn chapter_content(document: &Html, filename: &str, notes: &notes::NotesMap) -> String {
+    let selector = Selector::parse("body").unwrap();
+    let body = document.select(&selector).next().unwrap();
+    notes::attach_popovers(filename, &body, notes)
 }
 
-fn process_toc(input_file: &str, metadata: &HashMap<&str, String>, key: &str, output_root: &Path) {
+fn process_toc(
+    input_file: &str,
+    metadata: &BookMetadata,
+    key: &str,
+    output_root: &Path,
+    tera: &Tera,
+    index: &BookIndex,
+    minifier: &minify::Minifier,
+) {
     let doc = EpubDoc::new(input_file);
     assert!(doc.is_ok());
     let mut doc = doc.unwrap();
-    let mut ctx = Context::new();
-    for (key, val) in metadata.into_iter() {
-        ctx.insert(key, &val);
-    }
-
-    let mut chapter = HashMap::new();
-    chapter.insert("title", "Table of Contents");
-    chapter.insert("filename", "toc.html");
-
-    ctx.insert("chapter", &chapter);
+    let mut ctx = metadata_context(metadata);
 
     //  write fragment
-    println!("toc key {}", &key);
+    debug!("toc key {}", &key);
 
-    let str_data = doc.get_resource_str(key);
+    let source = doc.get_resource_str(key).unwrap();
+    let path = doc.resources[key].0.to_string_lossy().into_owned();
 
-    let mut fixed_content = str_data.unwrap().replace("../images", "images");
-    fixed_content = fixed_content.replace(".xhtml", ".html");
+    let fixed_content = convert_chapter(&source, &path, &index.outputs);
 
     let document = Html::parse_document(&fixed_content);
-    let selector = Selector::parse("body").unwrap();
-    let body = document.select(&selector).next().unwrap();
-    ctx.insert("content", &body.inner_html());
+    let filename = extract_filename(Path::new(&path));
+    ctx.insert(
+        "content",
+        &chapter_content(&document, &filename, &index.notes),
+    );
+
+    let mut chapter = chapter_context(&fixed_content, &path, index);
+    chapter.insert("title", "Table of Contents".to_string());
+    chapter.insert("filename", "toc.html".to_string());
+    if let Some(lang) = chapter.get("lang") {
+        ctx.insert("dir", text_direction(lang));
+        ctx.insert("lang", lang);
+    }
+    ctx.insert("chapter", &chapter);
+
+    ctx.insert(
+        "stylesheets",
+        &chapter_stylesheets(&document, &path, &index.outputs),
+    );
 
-    let rendered = TERA
+    let rendered = tera
         .render("page.html", &ctx)
         .expect("Failed to render template");
+    let rendered = minifier.html("toc.html", rendered);
 
     let fragment_filename = output_root.join("toc.html");
     let f = fs::File::create(&fragment_filename);
     assert!(f.is_ok());
     let mut f = f.unwrap();
-    let _resp = f.write_all(&rendered.as_bytes());
+    let _resp = f.write_all(rendered.as_bytes());
 }
 
 fn process_html_resource(
     input_file: &str,
-    metadata: &HashMap<&str, String>,
+    metadata: &BookMetadata,
     key: &str,
-    path: &str,
     output_root: &Path,
+    tera: &Tera,
+    index: &BookIndex,
+    minifier: &minify::Minifier,
 ) -> usize {
     let doc = EpubDoc::new(input_file);
     assert!(doc.is_ok());
     let mut doc = doc.unwrap();
+    let path = doc.resources[key].0.to_string_lossy().into_owned();
+    let path = path.as_str();
     let filename = Path::new(path)
         .file_name()
         .and_then(OsStr::to_str)
         .unwrap_or_default();
 
-    let mut ctx = Context::new();
-    for (key, val) in metadata.into_iter() {
-        ctx.insert(key, &val);
-    }
+    let mut ctx = metadata_context(metadata);
 
-    let mut chapter = HashMap::new();
     let new_path = replace_if(filename.to_string(), ".xhtml", ".html");
-    chapter.insert("title", "");
-    chapter.insert("filename", &new_path);
-    ctx.insert("chapter", &chapter);
 
-    let str_data = doc.get_resource_str(key);
-    let mut fixed_content = str_data.unwrap().replace("../images", "images");
+    let source = doc.get_resource_str(key).unwrap();
+    let mut fixed_content = convert_chapter(&source, path, &index.outputs);
     let mut i = 0;
 
-    let link_selector = Selector::parse("a").unwrap();
-    let total_links = Html::parse_document(&fixed_content)
-        .select(&link_selector)
-        .count();
+    let total_links = count_links(&fixed_content);
 
-    while fixed_content.contains("</p>") {
-        i = i + 1;
+    // section anchors would shift the absolutely placed text of fixed pages
+    while !index.pages.contains_key(key) && fixed_content.contains("</p>") {
+        i += 1;
         let anchor = format!(
             "<a class=\"para-anchor\" id=\"para-{}\" href=\"#para-{}\">&sect;</a>[/p]",
             &i, &i
@@ -441,13 +818,25 @@ fn process_html_resource(
         fixed_content = fixed_content.replacen("</p>", &anchor, 1);
     }
     fixed_content = fixed_content.replace("[/p]", "</p>");
-    fixed_content = fixed_content.replace(".xhtml", ".html"); // needed because some epubs have broken XHTML inside them.
 
     let document = Html::parse_document(&fixed_content);
-    let selector = Selector::parse("body").unwrap();
-    let body = document.select(&selector).next().unwrap();
+    ctx.insert(
+        "content",
+        &chapter_content(&document, &new_path, &index.notes),
+    );
+    ctx.insert(
+        "stylesheets",
+        &chapter_stylesheets(&document, path, &index.outputs),
+    );
 
-    ctx.insert("content", &body.inner_html());
+    let mut chapter = chapter_context(&fixed_content, path, index);
+    chapter.insert("title", String::new());
+    chapter.insert("filename", new_path.clone());
+    if let Some(lang) = chapter.get("lang") {
+        ctx.insert("dir", text_direction(lang));
+        ctx.insert("lang", lang);
+    }
+    ctx.insert("chapter", &chapter);
 
     let current_chapter_position = &doc
         .spine
@@ -455,6 +844,22 @@ fn process_html_resource(
         .position(|ref mut r| r.as_str() == key)
         .unwrap();
 
+    let title_selector = Selector::parse("title").unwrap();
+    let chapter_title = document
+        .select(&title_selector)
+        .next()
+        .map(|t| t.text().collect::<String>().trim().to_string())
+        .unwrap_or_default();
+    ctx.insert(
+        "json_ld",
+        &structured_data::chapter_json_ld(
+            metadata,
+            &chapter_title,
+            &new_path,
+            current_chapter_position + 1,
+        ),
+    );
+
     if (current_chapter_position + 1) < doc.spine.len() {
         let next_chapter_id = &doc.spine[current_chapter_position + 1];
         let next_chapter = &doc.resources.get(next_chapter_id);
@@ -475,19 +880,45 @@ fn process_html_resource(
         }
     }
 
-    let rendered = TERA
-        .render("page.html", &ctx)
+    if let Some(overlay) = index.overlays.get(key) {
+        ctx.insert("media_overlay", overlay);
+    }
+
+    let template = match index.pages.get(key) {
+        Some(page) => {
+            let (width, height) = fixed_layout::viewport(&document, path);
+            ctx.insert(
+                "fixed",
+                &fixed_layout::FixedPage {
+                    width,
+                    height,
+                    ..page.clone()
+                },
+            );
+            "fixed.html"
+        }
+        None => "page.html",
+    };
+    let rendered = tera
+        .render(template, &ctx)
         .expect("Failed to render template");
+    let rendered = minifier.html(&new_path, rendered);
 
-    let fragment_filename = output_root.join(&filename.replace(".xhtml", ".html"));
+    let fragment_filename = output_root.join(filename.replace(".xhtml", ".html"));
     let f = fs::File::create(&fragment_filename);
     assert!(f.is_ok());
     let mut f = f.unwrap();
-    let _resp = f.write_all(&rendered.as_bytes());
-    return total_links;
+    let _resp = f.write_all(rendered.as_bytes());
+    total_links
 }
 
-fn compress_image_resource(input_file: &str, key: &str, path: &str, output_root: &Path) {
+fn compress_image_resource(
+    input_file: &str,
+    key: &str,
+    path: &str,
+    output_root: &Path,
+    resize: bool,
+) {
     let doc = EpubDoc::new(input_file);
     assert!(doc.is_ok());
     let mut doc = doc.unwrap();
@@ -504,7 +935,7 @@ fn compress_image_resource(input_file: &str, key: &str, path: &str, output_root:
     let f = fs::File::create(&raw_filename);
     assert!(f.is_ok());
     let mut f = f.expect("writing raw filename");
-    let _resp = f.write_all(&data.unwrap().as_slice());
+    let _resp = f.write_all(data.unwrap().as_slice());
 
     let imgr = image::open(raw_filename);
     match imgr {
@@ -514,7 +945,7 @@ fn compress_image_resource(input_file: &str, key: &str, path: &str, output_root:
                 .join("images")
                 .join(format!("{}.{}", &key, &ext)); // pay attention to this, it might be the wrong name.
 
-            if width > MAX_WIDTH {
+            if resize && width > MAX_WIDTH {
                 let resized = img.resize(MAX_WIDTH, MAX_HEIGHT, FilterType::Lanczos3);
                 resized
                     .save(&compressed_filename)
@@ -525,12 +956,11 @@ fn compress_image_resource(input_file: &str, key: &str, path: &str, output_root:
                 let f = fs::File::create(&compressed_filename);
                 assert!(f.is_ok());
                 let mut f = f.expect("error writting compressed file");
-                let _resp = f.write_all(&data.unwrap().as_slice());
+                let _resp = f.write_all(data.unwrap().as_slice());
             }
         }
         Err(e) => {
-            println!("Error with image {}: {}", &path, &e);
-            println!("Just copying it...");
+            warn!("Can't decode image {}: {}, copying it as is", &path, &e);
             let data = doc.get_resource(key);
             let compressed_filename = output_root
                 .join("images")
@@ -539,22 +969,54 @@ fn compress_image_resource(input_file: &str, key: &str, path: &str, output_root:
             let f = fs::File::create(&compressed_filename);
             assert!(f.is_ok());
             let mut f = f.expect("error writting file");
-            let _resp = f.write_all(&data.unwrap().as_slice());
+            let _resp = f.write_all(data.unwrap().as_slice());
         }
     }
 }
 
-fn copy_template_resources(output_root: &Path) {
-    println!("Copying static resources...");
-    let mut options = CopyOptions::new();
-    options.copy_inside = true;
-    options.overwrite = true;
-    let handle = |process_info: TransitProcess| {
-        println!("  copy: {}", process_info.file_name);
-        fs_extra::dir::TransitProcessResult::ContinueOrAbort
-    };
-    copy_with_progress("static", output_root.join("resources"), &options, handle)
-        .expect("failed copying static resource");
+fn copy_image_resource(input_file: &str, key: &str, path: &str, output_root: &Path) {
+    let mut doc = EpubDoc::new(input_file).expect("Can't open ePub");
+    let output = output_root.join(output_path(key, Path::new(path), "image/"));
+    let data = doc.get_resource(key).expect("Can't read image");
+    fs::write(output, data).expect("Can't write image");
+}
+
+fn process_svg_resource(
+    input_file: &str,
+    key: &str,
+    path: &str,
+    output_root: &Path,
+    outputs: &HashMap<String, String>,
+) {
+    let mut doc = EpubDoc::new(input_file).expect("Can't open ePub");
+    let output = output_path(key, Path::new(path), "image/");
+    let data = doc.get_resource(key).expect("Can't read image");
+    let svg = images::sanitize_svg(&data, path, &output, outputs);
+    fs::write(output_root.join(&output), svg).expect("Can't write image");
+}
+
+// Notes can live in any chapter, so they're all gathered before the first
+// chapter is rendered.
+fn collect_notes(input_file: &str, outputs: &HashMap<String, String>) -> notes::NotesMap {
+    let mut doc = EpubDoc::new(input_file).expect("Can't open epub");
+    let mut notes = notes::NotesMap::new();
+    let resources = doc.resources.clone();
+    for (key, (path, mime)) in resources.iter() {
+        if !mime.contains("html") {
+            continue;
+        }
+        if let Ok(html) = doc.get_resource_str(key) {
+            // warned about when the chapter itself is converted
+            let source = path.to_string_lossy();
+            let html = xhtml::convert(&html, &source)
+                .map(|(html, _)| html)
+                .unwrap_or(html);
+            let html = fix_chapter_links(&fix_image_links(&html, &source, outputs));
+            notes::collect_notes(&extract_filename(path), &html, &mut notes);
+        }
+    }
+    debug!("found {} notes", notes.len());
+    notes
 }
 
 fn generate_spine(book: &Book) {
@@ -574,113 +1036,472 @@ fn generate_spine(book: &Book) {
             .and_then(OsStr::to_str)
             .unwrap_or_default()
             .replace(".xhtml", ".html");
-        writer.write_record(&[&index, &filename]).expect("Can't write spine item");
+        writer.write_record([&index, &filename]).expect("Can't write spine item");
     }
     writer.flush().expect("Can't write spine.csv");
 }
 
-fn process_book(book: &Book) {
-    let doc = EpubDoc::new(&book.epub);
+fn process_book(book: &Book, dirs: &ResourceDirs) -> BookReport {
+    let mut doc = EpubDoc::new(&book.epub).unwrap_or_else(|e| panic!("Can't open ePub: {}", e));
     let output_root = &book.output_folder;
-    assert!(doc.is_ok());
-    let doc = doc.unwrap();
     let output_root = Path::new(output_root);
 
     let _resp = fs::create_dir_all("temp/images/"); // needed because resize lib wants to work with files
 
     // assemble destination folder
-    let _resp = fs::remove_dir_all(&output_root);
-    let _resp = fs::create_dir_all(&output_root);
+    let _resp = fs::remove_dir_all(output_root);
+    let _resp = fs::create_dir_all(output_root);
     let _resp = fs::create_dir_all(output_root.join("images"));
     let _resp = fs::create_dir_all(output_root.join("resources"));
 
-    let metadata = get_metadata(&book);
-    println!(
+    let mut metadata = get_metadata(book);
+    metadata.theme = theme::book_theme(book);
+    info!(
         "Book: {} - {} ({})",
-        &metadata.get("title").unwrap(),
-        &metadata.get("author").unwrap(),
-        &metadata.get("date").unwrap()
+        &metadata.title, &metadata.author, &metadata.date
     );
-    println!("path: {}", book.epub);
+    debug!("path: {}", book.epub);
 
-    copy_template_resources(&output_root);
-    generate_spine(&book);
+    let stylesheets: Vec<String> = doc
+        .resources
+        .iter()
+        .filter(|(_, (_, mime))| mime.contains("css"))
+        .map(|(key, _)| key.clone())
+        .collect();
+    let keep_whitespace = book.minify
+        && stylesheets.iter().any(|key| {
+            doc.get_resource_str(key)
+                .is_ok_and(|css| minify::preserves_whitespace(&css))
+        });
+    let minifier = minify::Minifier::new(book.minify, keep_whitespace);
+    let dirs = dirs.for_book(book);
+    let tera = dirs.tera();
+    dirs.copy_static(output_root);
+    minifier.minify_folder(&output_root.join("resources").join("static"));
+    generate_spine(book);
 
     let num_resources = doc.resources.len();
-    println!("Total resources listed in Epub: {}", num_resources);
+    debug!("Total resources listed in Epub: {}", num_resources);
 
-    compress_cover(&book);
+    compress_cover(book, &metadata, &tera, &minifier);
 
     let resources = doc.resources.clone();
-    println!("Extracting resources...");
-    println!("Code:\n# -> images\n. -> HTML\nC -> CSS\n@ -> raw file resource\n");
+    let package = metadata::read_package(&mut doc);
+    let mut index = BookIndex {
+        outputs: output_paths(&doc),
+        notes: notes::NotesMap::new(),
+        encryption: fonts::Encryption::read(&book.epub, &metadata),
+        scoped_css: !book.unscoped_css,
+        pages: fixed_layout::fixed_pages(
+            &package,
+            &metadata.rendition,
+            &doc.resources
+                .iter()
+                .map(|(key, (path, _))| (key.clone(), extract_filename(path)))
+                .collect(),
+        ),
+        overlays: HashMap::new(),
+    };
+    if !index.pages.is_empty() {
+        info!("{} fixed-layout pages", index.pages.len());
+    }
+    for path in index.encryption.drm_files() {
+        warn!(
+            "{} is encrypted (DRM) and can't be converted, its output will be broken",
+            path
+        );
+    }
+    let fonts = fonts::convert_fonts(&book.epub, output_root, &index.encryption);
+    index
+        .outputs
+        .extend(fonts.iter().map(|(path, output)| (path.clone(), output.clone())));
+    let webps = if book.webp_gifs {
+        images::convert_gifs(&book.epub, output_root)
+    } else {
+        HashMap::new()
+    };
+    index
+        .outputs
+        .extend(webps.iter().map(|(path, output)| (path.clone(), output.clone())));
+    // after the WebP copies, so images in notes point at them too
+    index.notes = collect_notes(&book.epub, &index.outputs);
+    let overlays = media_overlay::write_overlays(&book.epub, &package, &index.outputs, output_root);
+    index.overlays = overlays.chapters;
+    info!("Extracting {} resources...", num_resources);
 
     let mut max_links = 0;
     let mut toc_id = "";
     for (key, val) in resources.iter() {
-        let path = &val.1;
-        let mime = &doc.get_resource_mime_by_path(&path).unwrap_or_default();
-
-        if mime.contains("image/") && !mime.contains("gif") {
-            print!("#");
-            compress_image_resource(&book.epub, &key, &path, &output_root);
+        let path = val.0.to_str().unwrap_or_default();
+        let mime = &val.1;
+
+        if webps.contains_key(&css::percent_decode(path)) {
+            trace!("converted GIF {}", path);
+        } else if mime.contains("gif") {
+            // resizing would drop the animation
+            trace!("gif {}", path);
+            copy_image_resource(&book.epub, key, path, output_root);
+        } else if mime.contains("svg") {
+            trace!("svg {}", path);
+            process_svg_resource(&book.epub, key, path, output_root, &index.outputs);
+        } else if mime.contains("image/") {
+            trace!("image {}", path);
+            // fixed-layout pages place images at their pixel size
+            compress_image_resource(&book.epub, key, path, output_root, index.pages.is_empty());
         } else if mime.contains("html") {
-            print!(".");
-            let total_links =
-                process_html_resource(&book.epub, &metadata, &key, &path, &output_root);
+            trace!("html {}", path);
+            let total_links = process_html_resource(
+                &book.epub,
+                &metadata,
+                key,
+                output_root,
+                &tera,
+                &index,
+                &minifier,
+            );
             if max_links < total_links {
                 max_links = total_links;
                 toc_id = key;
-                println!("\nFOUND TOC {} LINKS IN {}", &max_links, &key);
+                debug!("found TOC candidate with {} links in {}", &max_links, &key);
             }
         } else if mime.contains("css") {
-            print!("C");
-            process_css_resource(&book.epub, &key, &path, &output_root);
+            trace!("css {}", path);
+            process_css_resource(
+                &book.epub,
+                key,
+                path,
+                output_root,
+                &index.outputs,
+                index.scoped_css,
+                &minifier,
+            );
+        } else if fonts.contains_key(&css::percent_decode(path)) {
+            trace!("converted font {}", path);
         } else {
-            print!("@");
-            copy_raw_resource(&book.epub, &key, &path, &output_root);
+            trace!("raw {}", path);
+            copy_raw_resource(&book.epub, key, path, output_root, &index.encryption);
         }
-        let _r = io::stdout().flush();
     }
 
-    process_manifest(&book.epub, &metadata, &output_root);
-    copy_index_to_cover(&output_root);
-    move_service_worker(&output_root);
-
-    if toc_id != "" {
-        process_toc(&book.epub, &metadata, &toc_id, &output_root);
+    process_manifest(&book.epub, &metadata, output_root, &tera);
+    process_metadata_json(&metadata, output_root);
+    copy_index_to_cover(output_root);
+    move_service_worker(output_root);
+    media_overlay::write_precache_manifest(output_root, &overlays.files);
+
+    if !toc_id.is_empty() {
+        process_toc(
+            &book.epub,
+            &metadata,
+            toc_id,
+            output_root,
+            &tera,
+            &index,
+            &minifier,
+        );
     } else {
-        println!("book has no TOC, will link to cover");
+        warn!("{} has no TOC, will link to cover", &book.epub);
         fs::copy(output_root.join("cover.html"), output_root.join("toc.html"))
             .expect("Can't create toc.html");
     }
+
+    let audit = accessibility::audit(output_root);
+    info!(
+        "Accessibility score {} ({} issues in {} checks)",
+        audit.score,
+        audit.issues.len(),
+        audit.checked
+    );
+    for (kind, count) in audit.counts.iter() {
+        warn!("{} accessibility issues of kind {}", count, kind);
+    }
+    accessibility::write_report(output_root, &tera, metadata_context(&metadata), &audit);
+
+    let mut report = BookReport {
+        minified: minifier.report(),
+        accessibility: Some(audit.summary()),
+        ..Default::default()
+    };
+    if let Some(minified) = &report.minified {
+        info!(
+            "Minified {} files: {} -> {} bytes",
+            minified.files, minified.original_bytes, minified.minified_bytes
+        );
+    }
+
+    // last, so it sees every file
+    if book.precompress {
+        let precompressed = compress::precompress(output_root);
+        info!(
+            "Precompressed {} files ({} skipped): {} bytes gzip, {} bytes brotli",
+            precompressed.files.len(),
+            precompressed.skipped,
+            precompressed.gzip_bytes,
+            precompressed.brotli_bytes
+        );
+        report.precompressed = Some(precompressed);
+    }
+    report
+}
+
+// Runs `f`, turning a panic into an error message so one broken book
+// doesn't take a whole batch down with it.
+fn catch_failure<T, F: FnOnce() -> T>(f: F) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|e| {
+        if let Some(message) = e.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = e.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "conversion failed".to_string()
+        }
+    })
 }
 
-fn process_batch_job(path: &str) {
-    let file = File::open(path).expect("Can't open batch job json file");
+/// Converts the pending books of a batch job, returning how many of them
+/// failed.
+fn process_batch_job(path: &str) -> Result<u32, String> {
+    let file = File::open(path).map_err(|e| format!("Can't open batch job {}: {}", path, e))?;
 
-    let mut batch: BatchJob =
-        serde_json::from_reader(file).expect("Can't decode batch job json file");
+    let mut batch: BatchJob = serde_json::from_reader(file)
+        .map_err(|e| format!("Can't decode batch job {}: {}", path, e))?;
+    let dirs = ResourceDirs::new(&batch.templates, &batch.static_dir);
+    let mut failed = 0;
     for i in 0..batch.books.len() {
         if batch.books[i].status == "pending" {
             let book = batch.books[i].clone();
-            if Path::new(&book.epub).exists() {
-                process_book(&book);
-                batch.books[i].status = "success".to_string();
-                batch.report.success += 1;
-                println!("webapp: {}\n", &book.base_url);
+            let result = if Path::new(&book.epub).exists() {
+                // findings are recorded, the conversion goes ahead anyway
+                let report = preflight::preflight(&book.epub);
+                preflight::log_report(&book.epub, &report);
+                batch.report.preflight.insert(book.epub.clone(), report);
+                let ship_epub = batch.opds.is_some();
+                catch_failure(|| {
+                    let report = process_book(&book, &dirs);
+                    if ship_epub {
+                        opds::ship_epub(&book);
+                    }
+                    report
+                })
             } else {
-                batch.books[i].status = "error".to_string();
-                batch.books[i].error = format!("can't find book file: {}", &book.epub);
-                batch.report.error += 1;
+                Err(format!("can't find book file: {}", &book.epub))
+            };
+            match result {
+                Ok(report) => {
+                    if let Some(minified) = report.minified {
+                        batch.report.minified.insert(book.epub.clone(), minified);
+                    }
+                    if let Some(precompressed) = report.precompressed {
+                        batch
+                            .report
+                            .precompressed
+                            .insert(book.epub.clone(), precompressed);
+                    }
+                    if let Some(score) = report.accessibility {
+                        batch
+                            .report
+                            .accessibility
+                            .insert(book.epub.clone(), score);
+                    }
+                    batch.books[i].status = "success".to_string();
+                    batch.report.success += 1;
+                    info!("webapp: {}\n", &book.base_url);
+                }
+                Err(e) => {
+                    error!("{}: {}", &book.epub, &e);
+                    batch.books[i].status = "error".to_string();
+                    batch.books[i].error = e;
+                    batch.report.error += 1;
+                    failed += 1;
+                }
             }
         } else {
             batch.report.skipped += 1;
         }
 
-        let j = serde_json::to_string(&mut batch).expect("Can't serialize report");
+        let j = serde_json::to_string(&batch).expect("Can't serialize report");
         fs::write(path, &j).expect("Can't write batch json");
     }
+
+    // served from the same tree as the books, so compressed along with them
+    let precompress = batch.books.iter().any(|b| b.precompress);
+
+    if let Some(config) = &batch.library {
+        let files = library::process_library(config, &batch.books, &dirs);
+        if precompress {
+            let report = compress::precompress_files(Path::new(&config.output_folder), &files);
+            batch.report.library_precompressed = Some(report);
+        }
+    }
+
+    if let Some(config) = &batch.opds {
+        let files = opds::process_opds(config, &batch.books);
+        if precompress {
+            let report = compress::precompress_files(Path::new(&config.output_folder), &files);
+            batch.report.opds_precompressed = Some(report);
+        }
+    }
+
+    if precompress {
+        let j = serde_json::to_string(&batch).expect("Can't serialize report");
+        fs::write(path, &j).expect("Can't write batch json");
+    }
+
+    Ok(failed)
+}
+
+fn convert(args: &ArgMatches) -> i32 {
+    let epub = args.value_of("EPUB").expect("EPUB is required");
+    if !Path::new(epub).exists() {
+        error!("Can't find book file: {}", epub);
+        return EXIT_USAGE;
+    }
+
+    let book: Book = Book {
+        info_url: args.value_of("INFOURL").unwrap_or("").to_string(),
+        base_url: args.value_of("BASEURL").unwrap_or("").to_string(),
+        epub: epub.to_string(),
+        description: args.value_of("DESCRIPTION").unwrap_or("").to_string(),
+        output_folder: args
+            .value_of("OUTPUT")
+            .unwrap_or(DEFAULT_OUTPUT_FOLDER)
+            .to_string(),
+        status: "pending".to_string(),
+        error: "".to_string(),
+        language: args.value_of("LANGUAGE").unwrap_or("").to_string(),
+        templates: args.value_of("TEMPLATES").unwrap_or("").to_string(),
+        static_dir: args.value_of("STATIC").unwrap_or("").to_string(),
+        unscoped_css: args.is_present("UNSCOPED_CSS"),
+        minify: args.is_present("MINIFY"),
+        precompress: args.is_present("PRECOMPRESS"),
+        webp_gifs: args.is_present("WEBP_GIFS"),
+        ..Default::default()
+    };
+
+    match catch_failure(|| process_book(&book, &ResourceDirs::default())) {
+        Ok(_) => {
+            info!("webapp: {}", &book.output_folder);
+            EXIT_OK
+        }
+        Err(e) => {
+            error!("{}: {}", epub, e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn batch(args: &ArgMatches) -> i32 {
+    let path = args.value_of("JSON").expect("JSON is required");
+    match process_batch_job(path) {
+        Ok(0) => EXIT_OK,
+        Ok(failed) => {
+            error!("{} books failed to convert, see {}", failed, path);
+            EXIT_FAILURE
+        }
+        Err(e) => {
+            error!("{}", e);
+            EXIT_USAGE
+        }
+    }
+}
+
+fn inspect(args: &ArgMatches) -> i32 {
+    let epub = args.value_of("EPUB").expect("EPUB is required");
+    match inspect::inspect(epub) {
+        Ok(inspection) => {
+            if args.is_present("json") {
+                let j = serde_json::to_string_pretty(&inspection)
+                    .expect("Can't serialize inspection");
+                println!("{}", j);
+            } else {
+                inspect::print_table(&inspection);
+            }
+            EXIT_OK
+        }
+        Err(e) => {
+            error!("{}", e);
+            EXIT_USAGE
+        }
+    }
+}
+
+fn preflight(args: &ArgMatches) -> i32 {
+    let mut reports = BTreeMap::new();
+    for epub in args.values_of("EPUB").expect("EPUB is required") {
+        if !Path::new(epub).exists() {
+            error!("Can't find book file: {}", epub);
+            return EXIT_USAGE;
+        }
+        reports.insert(epub, preflight::preflight(epub));
+    }
+
+    if args.is_present("json") {
+        let j = serde_json::to_string_pretty(&reports).expect("Can't serialize preflight");
+        println!("{}", j);
+    } else {
+        for (epub, report) in reports.iter() {
+            preflight::log_report(epub, report);
+        }
+    }
+
+    if reports.values().any(|r| r.errors > 0) {
+        EXIT_FAILURE
+    } else {
+        EXIT_OK
+    }
+}
+
+fn validate(args: &ArgMatches) -> i32 {
+    let mut reports = vec![];
+    for folder in args.values_of("FOLDER").expect("FOLDER is required") {
+        match validate::validate(folder) {
+            Ok(report) => reports.push(report),
+            Err(e) => {
+                error!("{}", e);
+                return EXIT_USAGE;
+            }
+        }
+    }
+
+    if args.is_present("json") {
+        let j = serde_json::to_string_pretty(&reports).expect("Can't serialize validation");
+        println!("{}", j);
+    } else {
+        for report in reports.iter() {
+            validate::log_report(report);
+        }
+    }
+
+    if reports.iter().any(|r| r.errors > 0) {
+        EXIT_FAILURE
+    } else {
+        EXIT_OK
+    }
+}
+
+fn serve(args: &ArgMatches) -> i32 {
+    let folder = args.value_of("FOLDER").unwrap_or(DEFAULT_OUTPUT_FOLDER);
+    let port = match value_t!(args, "PORT", u16) {
+        Ok(port) => port,
+        Err(e) => {
+            error!("{}", e);
+            return EXIT_USAGE;
+        }
+    };
+    match serve::serve(Path::new(folder), port) {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            error!("{}", e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn eject(args: &ArgMatches) -> i32 {
+    let folder = args.value_of("FOLDER").unwrap_or(".");
+    info!("Ejecting built-in templates and static files to {}", folder);
+    templates::eject(Path::new(folder), args.is_present("force"));
+    EXIT_OK
 }
 
 fn main() {
@@ -688,43 +1509,134 @@ fn main() {
         (version: "2.0")
         (author: "Andre Alves Garzia <andre@andregarzia.com")
         (about: "Converts ePub books into PWAs")
-        (@arg INFOURL: -i --infourl +takes_value "Info URL for the book")
-        (@arg BASEURL: -u --baseurl +takes_value "Base URL for the book")
-        (@arg DESCRIPTION: -d --description +takes_value "Description for the book")
-        (@arg EBOOK: -e --epub +takes_value "Sets the epub file to use")
-        (@arg OUTPUT: -o --output +takes_value "Sets the output folder")
-        (@arg BATCH: -b --batch +takes_value "Pass a json for batch jobs")
-        (@arg debug: -v ... "Sets the level of debugging information")
+        (after_help: "Exit codes: 0 on success, 1 when a conversion fails, 2 for bad arguments or input files.")
+        (@setting SubcommandRequiredElseHelp)
+        (@setting VersionlessSubcommands)
+        (@arg verbose: -v --verbose ... +global "Shows more detail, repeat for even more")
+        (@arg quiet: -q --quiet +global conflicts_with[verbose] "Only shows errors")
+        (@subcommand convert =>
+            (about: "Converts a single ePub into a PWA")
+            (@arg EPUB: +required "The ePub file to convert")
+            (@arg OUTPUT: -o --output +takes_value "Output folder (default: web/)")
+            (@arg INFOURL: -i --infourl +takes_value "Info URL for the book")
+            (@arg BASEURL: -u --baseurl +takes_value "Base URL for the book")
+            (@arg DESCRIPTION: -d --description +takes_value "Description for the book")
+            (@arg LANGUAGE: -l --language +takes_value "Language, overriding the one in the ePub")
+            (@arg TEMPLATES: -t --templates +takes_value "Folder with templates overriding the built-in ones")
+            (@arg STATIC: -s --static +takes_value "Folder with static files overriding the built-in ones")
+            (@arg UNSCOPED_CSS: --("unscoped-css") "Links the book CSS as is instead of scoping it to the book content")
+            (@arg MINIFY: --minify "Minifies the generated HTML, CSS and JavaScript")
+            (@arg PRECOMPRESS: --precompress "Writes gzip and brotli copies of the text files for static servers")
+            (@arg WEBP_GIFS: --("webp-gifs") "Converts GIFs to animated WebP where that makes them smaller")
+        )
+        (@subcommand batch =>
+            (about: "Converts the pending books of a batch job json, updating its report")
+            (@arg JSON: +required "The batch job json")
+        )
+        (@subcommand inspect =>
+            (about: "Shows the metadata, manifest, spine, cover and TOC the converter would use")
+            (@arg EPUB: +required "The ePub file to inspect")
+            (@arg json: --json "Prints JSON instead of tables")
+        )
+        (@subcommand preflight =>
+            (about: "Checks ePubs for container, manifest, spine, MIME and XHTML problems before converting")
+            (@arg EPUB: +required ... "The ePub files to check")
+            (@arg json: --json "Prints the reports as JSON")
+        )
+        (@subcommand validate =>
+            (about: "Checks converted books for broken links, anchors, navigation and PWA files")
+            (@arg FOLDER: +required ... "Output folders to check")
+            (@arg json: --json "Prints the reports as JSON")
+        )
+        (@subcommand serve =>
+            (about: "Serves an output folder on localhost for previewing")
+            (@arg FOLDER: "The folder to serve (default: web/)")
+            (@arg PORT: -p --port +takes_value default_value("8000") "Port to listen on")
+        )
+        (@subcommand eject =>
+            (about: "Writes the built-in templates and static files out for customization")
+            (@arg FOLDER: "Folder to write templates/ and static/ into (default: current folder)")
+            (@arg force: -f --force "Overwrite files that already exist")
+        )
     )
-    .get_matches();
-
-    let batch = matches.value_of("BATCH");
-    match batch {
-        Some(json) => {
-            // batch processing.
-            process_batch_job(json);
-        }
-        None => {
-            // single book processing
-            let epub = matches
-                .value_of("EBOOK")
-                .expect("Must pass ePub file as argument.");
-            let output_folder = matches.value_of("OUTPUT").unwrap_or(DEFAULT_OUTPUT_FOLDER);
-            let info_url = matches.value_of("INFOURL").unwrap_or("");
-            let base_url = matches.value_of("INFOURL").unwrap_or("");
-            let description = matches.value_of("DESCRIPTION").unwrap_or("");
-
-            let book: Book = Book {
-                info_url: info_url.to_string(),
-                base_url: base_url.to_string(),
-                epub: epub.to_string(),
-                description: description.to_string(),
-                output_folder: output_folder.to_string(),
-                status: "pending".to_string(),
-                error: "".to_string(),
-            };
-
-            process_book(&book);
+    .get_matches_safe()
+    .unwrap_or_else(|e| match e.kind {
+        clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+        _ => {
+            eprintln!("{}", e.message);
+            process::exit(EXIT_USAGE);
         }
+    });
+
+    let (command, args) = matches.subcommand();
+    let args = args.expect("subcommand is required");
+    logging::init(logging::level_for(
+        args.occurrences_of("verbose"),
+        args.is_present("quiet"),
+    ));
+    // failures are reported by whoever catches them, only keep the
+    // location around for debugging
+    panic::set_hook(Box::new(|info| {
+        if let Some(location) = info.location() {
+            debug!("panicked at {}", location);
+        }
+    }));
+
+    let code = match command {
+        "convert" => convert(args),
+        "batch" => batch(args),
+        "inspect" => inspect(args),
+        "preflight" => preflight(args),
+        "validate" => validate(args),
+        "serve" => serve(args),
+        "eject" => eject(args),
+        _ => EXIT_USAGE,
+    };
+    log::logger().flush();
+    process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, EXIT_FAILURE, EXIT_OK};
+    use clap::{App, Arg};
+    use std::env;
+    use std::fs;
+
+    // A folder `validate` has nothing against, but for what `page` links to.
+    fn book_folder(name: &str, page: &str) -> String {
+        let folder = env::temp_dir().join(format!("epub2pwa-{}-{}", name, std::process::id()));
+        let _resp = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let manifest = r#"{"start_url": "index.html", "icons": [{"src": "icon.png"}]}"#;
+        fs::write(folder.join("manifest.webmanifest"), manifest).unwrap();
+        fs::write(folder.join("icon.png"), "").unwrap();
+        fs::write(folder.join("sw.js"), "").unwrap();
+        fs::write(folder.join("spine.csv"), "0,ch1.html\n").unwrap();
+        fs::write(folder.join("index.html"), "<a href=\"ch1.html\">start</a>").unwrap();
+        fs::write(folder.join("ch1.html"), page).unwrap();
+        folder.to_string_lossy().into_owned()
+    }
+
+    fn validate_folder(folder: &str) -> i32 {
+        let matches = App::new("validate")
+            .arg(Arg::with_name("FOLDER").multiple(true))
+            .arg(Arg::with_name("json").long("json"))
+            .get_matches_from(vec!["validate", "--json", folder]);
+        let code = validate(&matches);
+        let _resp = fs::remove_dir_all(folder);
+        code
+    }
+
+    #[test]
+    fn validate_passes_books_with_working_anchors() {
+        let folder = book_folder("anchors-ok", r#"<p id="a">x</p><a href="ch1.html#a">a</a>"#);
+        assert_eq!(validate_folder(&folder), EXIT_OK);
+    }
+
+    #[test]
+    fn validate_fails_on_missing_anchors() {
+        let folder = book_folder("anchors-missing", r#"<a href="index.html#nowhere">a</a>"#);
+        assert_eq!(validate_folder(&folder), EXIT_FAILURE);
     }
 }
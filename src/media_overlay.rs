@@ -0,0 +1,239 @@
+// EPUB3 media overlays (read-along narration).
+//
+// A chapter with narration names a SMIL document in its manifest item's
+// `media-overlay` attribute. Each `<par>` in there pairs an element of the
+// chapter (`<text src="chapter.xhtml#id">`) with a clip of an audio file
+// (`<audio src clipBegin clipEnd>`). They are written out per chapter as
+// JSON for overlay.js, which plays the clips in order and highlights the
+// element being read.
+
+use epub::doc::EpubDoc;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent};
+
+use super::css::{self, percent_decode};
+use super::metadata::Package;
+
+/// One `<par>`: the element with `id` is read from `begin` to `end`
+/// seconds of `audio`, to the end of the file when `end` is missing.
+#[derive(Serialize, Clone, Default)]
+pub struct Fragment {
+    pub id: String,
+    pub audio: String,
+    pub begin: f64,
+    pub end: Option<f64>,
+}
+
+/// SMIL clock values: `1:02:03.5`, `02:03.5`, `3.5s`, `350ms`, `2min`,
+/// `1h` or a bare number of seconds.
+pub fn parse_clock(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if value.contains(':') {
+        let mut seconds = 0.0;
+        for part in value.split(':') {
+            seconds = seconds * 60.0 + part.trim().parse::<f64>().ok()?;
+        }
+        return Some(seconds);
+    }
+    let units = [("ms", 0.001), ("min", 60.0), ("h", 3600.0), ("s", 1.0)];
+    for (unit, factor) in units.iter() {
+        if let Some(number) = value.strip_suffix(unit) {
+            return number.trim().parse::<f64>().ok().map(|n| n * factor);
+        }
+    }
+    value.parse().ok()
+}
+
+fn attribute(attributes: &[xml::attribute::OwnedAttribute], name: &str) -> Option<String> {
+    attributes
+        .iter()
+        .find(|a| a.name.local_name == name)
+        .map(|a| a.value.clone())
+}
+
+/// The `<par>` elements of a SMIL document as (text target, fragment)
+/// pairs. Both `src` attributes are resolved against `path`, the SMIL
+/// document's own path, and the audio is pointed at its output through
+/// `outputs`.
+pub fn parse_smil(
+    smil: &[u8],
+    path: &str,
+    outputs: &HashMap<String, String>,
+) -> Vec<(String, Fragment)> {
+    let mut pars = vec![];
+    let mut text: Option<String> = None;
+    let mut fragment: Option<Fragment> = None;
+    for event in EventReader::new(smil) {
+        match event {
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) => match name.local_name.as_str() {
+                "par" => {
+                    text = None;
+                    fragment = None;
+                }
+                "text" => text = attribute(&attributes, "src"),
+                "audio" => {
+                    let src = attribute(&attributes, "src").unwrap_or_default();
+                    let target = css::resolve_path(path, &percent_decode(&src));
+                    let audio = match outputs.get(&target) {
+                        Some(audio) => audio.clone(),
+                        None => {
+                            warn!("{} references missing audio {}", path, src);
+                            continue;
+                        }
+                    };
+                    let clock =
+                        |name: &str| attribute(&attributes, name).and_then(|v| parse_clock(&v));
+                    fragment = Some(Fragment {
+                        id: String::new(),
+                        audio,
+                        begin: clock("clipBegin").unwrap_or(0.0),
+                        end: clock("clipEnd"),
+                    });
+                }
+                _ => {}
+            },
+            Ok(XmlEvent::EndElement { name }) if name.local_name == "par" => {
+                let src = text.take().unwrap_or_default();
+                if let (Some(hash), Some(mut fragment)) = (src.find('#'), fragment.take()) {
+                    fragment.id = percent_decode(&src[hash + 1..]);
+                    let target = css::resolve_path(path, &percent_decode(&src[..hash]));
+                    pars.push((target, fragment));
+                }
+            }
+            Err(e) => {
+                warn!("Can't parse {}: {}", path, e);
+                break;
+            }
+            _ => {}
+        }
+    }
+    pars
+}
+
+#[derive(Default)]
+pub struct Overlays {
+    /// Overlay JSON of each narrated chapter, keyed by manifest id.
+    pub chapters: HashMap<String, String>,
+    /// The JSON and audio files, for the service worker to precache.
+    pub files: BTreeSet<String>,
+}
+
+/// Writes `overlays/<chapter>.json` for every chapter with a media overlay.
+pub fn write_overlays(
+    epub: &str,
+    package: &Package,
+    outputs: &HashMap<String, String>,
+    output_root: &Path,
+) -> Overlays {
+    let mut written = Overlays::default();
+    let mut doc = EpubDoc::new(epub).expect("Can't open ePub");
+    let resources = doc.resources.clone();
+    let narrated: Vec<_> = package
+        .manifest
+        .iter()
+        .filter(|item| !item.media_overlay.is_empty())
+        .collect();
+    if narrated.is_empty() {
+        return written;
+    }
+    info!("Reading media overlays of {} chapters...", narrated.len());
+    let _resp = fs::create_dir_all(output_root.join("overlays"));
+
+    for item in narrated {
+        let chapter = resources.get(&item.id);
+        let smil = resources.get(&item.media_overlay);
+        let (chapter, smil) = match (chapter, smil) {
+            (Some(chapter), Some(smil)) => (chapter, smil),
+            _ => {
+                warn!("{} names missing media overlay {}", item.href, item.media_overlay);
+                continue;
+            }
+        };
+        let chapter_path = percent_decode(&chapter.0.to_string_lossy());
+        let smil_path = percent_decode(&smil.0.to_string_lossy());
+        let data = match doc.get_resource(&item.media_overlay) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let fragments: Vec<Fragment> = parse_smil(&data, &smil_path, outputs)
+            .into_iter()
+            .filter(|(target, _)| *target == chapter_path)
+            .map(|(_, fragment)| fragment)
+            .collect();
+        if fragments.is_empty() {
+            warn!("{} has no narration for {}", smil_path, chapter_path);
+            continue;
+        }
+        let name = outputs
+            .get(&chapter_path)
+            .map(|output| output.trim_end_matches(".html").to_string())
+            .unwrap_or_else(|| item.id.clone());
+        let output = format!("overlays/{}.json", name);
+        debug!("{}: {} narrated fragments", chapter_path, fragments.len());
+        fs::write(
+            output_root.join(&output),
+            serde_json::to_string(&fragments).expect("Can't serialize overlay"),
+        )
+        .expect("Can't write overlay");
+        written
+            .files
+            .extend(fragments.iter().map(|f| f.audio.clone()));
+        written.files.insert(output.clone());
+        written.chapters.insert(item.id.clone(), output);
+    }
+    written
+}
+
+/// Writes `precache-manifest.js`, which sw.js imports, listing `files`
+/// with a content hash so the service worker refetches them when they
+/// change. Books without narration get an empty list.
+pub fn write_precache_manifest(output_root: &Path, files: &BTreeSet<String>) {
+    let mut entries = vec![];
+    for file in files {
+        if let Ok(data) = fs::read(output_root.join(file)) {
+            entries.push(json!({
+                "url": file,
+                "revision": sha1_smol::Sha1::from(&data).digest().to_string(),
+            }));
+        }
+    }
+    let script = format!(
+        "self.__precacheManifest = {};\n",
+        serde_json::to_string(&entries).expect("Can't serialize precache manifest")
+    );
+    fs::write(output_root.join("precache-manifest.js"), script)
+        .expect("Can't write precache manifest");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_clock;
+
+    #[test]
+    fn parses_full_and_partial_clock_values() {
+        assert_eq!(parse_clock("1:02:03.5"), Some(3723.5));
+        assert_eq!(parse_clock("02:03.5"), Some(123.5));
+        assert_eq!(parse_clock(" 0:00:01 "), Some(1.0));
+    }
+
+    #[test]
+    fn parses_timecounts() {
+        assert_eq!(parse_clock("3.5s"), Some(3.5));
+        assert_eq!(parse_clock("250ms"), Some(0.25));
+        assert_eq!(parse_clock("2min"), Some(120.0));
+        assert_eq!(parse_clock("1h"), Some(3600.0));
+        assert_eq!(parse_clock("12.25"), Some(12.25));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert_eq!(parse_clock(""), None);
+        assert_eq!(parse_clock("soon"), None);
+        assert_eq!(parse_clock("1:xx"), None);
+        assert_eq!(parse_clock("3 days"), None);
+    }
+}
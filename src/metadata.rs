@@ -0,0 +1,582 @@
+// Typed view of the OPF `<metadata>` block.
+//
+// The epub crate flattens metadata into name -> values and drops the
+// `refines` links EPUB3 uses for roles, series positions and so on, so the
+// package document is read again here with xml-rs. The manifest and spine
+// are kept as written too, with the `properties` and `linear` attributes
+// the epub crate leaves out.
+
+use epub::doc::EpubDoc;
+use serde_json::Value;
+use std::collections::HashMap;
+use tera::Context;
+use xml::reader::{EventReader, XmlEvent};
+
+use super::theme::Theme;
+use super::{structured_data, Book};
+
+#[derive(Serialize, Clone, Default)]
+pub struct Identifier {
+    pub scheme: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct Contributor {
+    pub name: String,
+    pub file_as: String,
+    pub role: String,
+    pub role_name: String,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct Collection {
+    pub name: String,
+    pub kind: String,
+    pub position: String,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct Accessibility {
+    pub access_modes: Vec<String>,
+    pub access_modes_sufficient: Vec<String>,
+    pub features: Vec<String>,
+    pub hazards: Vec<String>,
+    pub summary: String,
+    pub conforms_to: Vec<String>,
+}
+
+/// EPUB3 rendition properties, empty when the OPF doesn't set them.
+/// `layout` is `pre-paginated` for fixed-layout books.
+#[derive(Serialize, Clone, Default)]
+pub struct Rendition {
+    pub layout: String,
+    pub spread: String,
+    pub orientation: String,
+    /// `page-progression-direction` of the spine.
+    pub direction: String,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct BookMetadata {
+    pub title: String,
+    pub author: String,
+    pub date: String,
+    pub description: String,
+    pub info_url: String,
+    pub base_url: String,
+    pub language: String,
+    pub languages: Vec<String>,
+    pub direction: String,
+    pub unique_identifier: String,
+    pub identifiers: Vec<Identifier>,
+    pub isbn: String,
+    pub uuid: String,
+    pub publisher: String,
+    pub rights: String,
+    pub subjects: Vec<String>,
+    pub modified: String,
+    pub creators: Vec<Contributor>,
+    pub contributors: Vec<Contributor>,
+    pub series: Option<Collection>,
+    pub collections: Vec<Collection>,
+    pub accessibility: Accessibility,
+    pub rendition: Rendition,
+    /// `media:active-class`, the class media overlays put on the text
+    /// being read.
+    pub media_active_class: String,
+    /// Filled in by `process_book`, the defaults everywhere else.
+    pub theme: Theme,
+}
+
+// One direct child of `<metadata>`, attributes keyed by local name.
+struct MetaElement {
+    name: String,
+    attributes: HashMap<String, String>,
+    text: String,
+}
+
+impl MetaElement {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|s| s.as_str())
+    }
+}
+
+/// A `<manifest>` item, `href` as written in the OPF.
+#[derive(Serialize, Clone, Default)]
+pub struct ManifestItem {
+    pub id: String,
+    pub href: String,
+    pub media_type: String,
+    pub properties: Vec<String>,
+    /// Id of the SMIL media overlay narrating this item.
+    pub media_overlay: String,
+}
+
+/// A spine `<itemref>`. `linear="no"` marks content outside the reading order.
+#[derive(Serialize, Clone, Default)]
+pub struct SpineItem {
+    pub idref: String,
+    pub linear: bool,
+    /// e.g. `page-spread-left` or `rendition:layout-pre-paginated`.
+    pub properties: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Package {
+    unique_identifier: String,
+    elements: Vec<MetaElement>,
+    pub manifest: Vec<ManifestItem>,
+    pub spine: Vec<SpineItem>,
+    /// The NCX id from `<spine toc="...">`.
+    pub spine_toc: String,
+    /// `<spine page-progression-direction="...">`.
+    pub page_progression: String,
+}
+
+impl Package {
+    /// Whether `<metadata>` has a direct child called `name`, e.g. `title`.
+    pub fn has_element(&self, name: &str) -> bool {
+        self.elements
+            .iter()
+            .any(|e| e.name == name && !e.text.is_empty())
+    }
+
+    /// The manifest id from `<meta name="cover">`.
+    pub fn cover_id(&self) -> String {
+        first_meta(&self.elements, "cover")
+    }
+}
+
+fn attribute(attributes: &[xml::attribute::OwnedAttribute], name: &str) -> String {
+    attributes
+        .iter()
+        .find(|a| a.name.local_name == name)
+        .map(|a| a.value.clone())
+        .unwrap_or_default()
+}
+
+pub fn parse_package(opf: &[u8]) -> Package {
+    let mut package = Package::default();
+    let mut in_metadata = false;
+    let mut depth = 0;
+    let mut current: Option<MetaElement> = None;
+
+    for event in EventReader::new(opf) {
+        match event {
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) => {
+                if name.local_name == "package" {
+                    package.unique_identifier = attribute(&attributes, "unique-identifier");
+                } else if name.local_name == "item" && !in_metadata {
+                    package.manifest.push(ManifestItem {
+                        id: attribute(&attributes, "id"),
+                        href: attribute(&attributes, "href"),
+                        media_type: attribute(&attributes, "media-type"),
+                        properties: attribute(&attributes, "properties")
+                            .split_whitespace()
+                            .map(|p| p.to_string())
+                            .collect(),
+                        media_overlay: attribute(&attributes, "media-overlay"),
+                    });
+                } else if name.local_name == "spine" {
+                    package.spine_toc = attribute(&attributes, "toc");
+                    package.page_progression =
+                        attribute(&attributes, "page-progression-direction");
+                } else if name.local_name == "itemref" {
+                    package.spine.push(SpineItem {
+                        idref: attribute(&attributes, "idref"),
+                        linear: attribute(&attributes, "linear") != "no",
+                        properties: attribute(&attributes, "properties")
+                            .split_whitespace()
+                            .map(|p| p.to_string())
+                            .collect(),
+                    });
+                } else if name.local_name == "metadata" {
+                    in_metadata = true;
+                    depth = 0;
+                } else if in_metadata {
+                    depth += 1;
+                    if depth == 1 {
+                        current = Some(MetaElement {
+                            name: name.local_name,
+                            attributes: attributes
+                                .into_iter()
+                                .map(|a| (a.name.local_name, a.value))
+                                .collect(),
+                            text: String::new(),
+                        });
+                    }
+                }
+            }
+            Ok(XmlEvent::Characters(s)) | Ok(XmlEvent::CData(s)) => {
+                if let Some(e) = current.as_mut() {
+                    e.text.push_str(&s);
+                }
+            }
+            Ok(XmlEvent::EndElement { name }) => {
+                if name.local_name == "metadata" && depth == 0 {
+                    in_metadata = false;
+                } else if in_metadata {
+                    if depth == 1 {
+                        if let Some(mut e) = current.take() {
+                            e.text = e.text.trim().to_string();
+                            package.elements.push(e);
+                        }
+                    }
+                    depth -= 1;
+                }
+            }
+            Err(e) => {
+                warn!("Can't parse OPF: {}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    package
+}
+
+/// `rtl` for scripts written right to left, judged by the primary language subtag.
+pub fn text_direction(language: &str) -> &'static str {
+    let primary = language
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    match primary.as_str() {
+        "ar" | "arc" | "ckb" | "dv" | "fa" | "he" | "iw" | "ks" | "ku" | "ps" | "sd" | "syr"
+        | "ug" | "ur" | "yi" => "rtl",
+        _ => "ltr",
+    }
+}
+
+fn role_name(code: &str) -> &'static str {
+    match code {
+        "aut" => "Author",
+        "trl" => "Translator",
+        "ill" => "Illustrator",
+        "edt" => "Editor",
+        "nrt" => "Narrator",
+        "art" => "Artist",
+        "pht" => "Photographer",
+        "aui" => "Author of introduction",
+        "aft" => "Author of afterword",
+        "pbl" => "Publisher",
+        "bkp" => "Book producer",
+        "cov" => "Cover designer",
+        "dsr" => "Designer",
+        _ => "Contributor",
+    }
+}
+
+fn identifier_scheme(element: &MetaElement, refinements: &[&MetaElement]) -> (String, String) {
+    let value = element.text.clone();
+    let lower = value.to_lowercase();
+    let declared = element
+        .attr("scheme")
+        .map(|s| s.to_lowercase())
+        .or_else(|| {
+            refinements
+                .iter()
+                .find(|r| r.attr("property") == Some("identifier-type"))
+                .map(|r| r.text.to_lowercase())
+        })
+        .unwrap_or_default();
+
+    if structured_data::isbn_from_identifier(&value).is_some()
+        && (declared.contains("isbn") || lower.contains("isbn") || declared.is_empty())
+    {
+        ("isbn".to_string(), value)
+    } else if declared.contains("uuid") || lower.starts_with("urn:uuid:") {
+        ("uuid".to_string(), value)
+    } else if declared.contains("doi") || lower.starts_with("doi:") {
+        ("doi".to_string(), value)
+    } else if declared.is_empty() {
+        ("other".to_string(), value)
+    } else {
+        (declared, value)
+    }
+}
+
+fn contributor(
+    element: &MetaElement,
+    refinements: &[&MetaElement],
+    default_role: &str,
+) -> Contributor {
+    let refined = |property: &str| {
+        refinements
+            .iter()
+            .find(|r| r.attr("property") == Some(property))
+            .map(|r| r.text.clone())
+    };
+    let role = element
+        .attr("role")
+        .map(|s| s.to_string())
+        .or_else(|| refined("role"))
+        .unwrap_or_else(|| default_role.to_string());
+    let file_as = element
+        .attr("file-as")
+        .map(|s| s.to_string())
+        .or_else(|| refined("file-as"))
+        .unwrap_or_default();
+
+    Contributor {
+        name: element.text.clone(),
+        file_as,
+        role_name: role_name(&role).to_string(),
+        role,
+    }
+}
+
+// EPUB3 puts accessibility metadata in `<meta property>`, EPUB2 in `<meta name content>`.
+fn meta_values(elements: &[MetaElement], property: &str) -> Vec<String> {
+    elements
+        .iter()
+        .filter(|e| e.name == "meta" && e.attr("refines").is_none())
+        .filter_map(|e| {
+            if e.attr("property") == Some(property) {
+                Some(e.text.clone())
+            } else if e.attr("name") == Some(property) {
+                e.attr("content").map(|s| s.to_string())
+            } else {
+                None
+            }
+        })
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn first_meta(elements: &[MetaElement], property: &str) -> String {
+    meta_values(elements, property)
+        .into_iter()
+        .next()
+        .unwrap_or_default()
+}
+
+pub fn read_package(doc: &mut EpubDoc<::std::io::BufReader<::std::fs::File>>) -> Package {
+    let root_file = doc.root_file.clone();
+    match doc.get_resource_by_path(&root_file) {
+        Ok(opf) => parse_package(&opf),
+        Err(e) => {
+            warn!("Can't read OPF {}: {}", root_file.display(), e);
+            Package::default()
+        }
+    }
+}
+
+/// Reads the full OPF metadata for `book`, with the batch fields
+/// (description and URLs) merged in.
+///
+/// Fields the OPF parse leaves empty, as when xml-rs can't read the OPF,
+/// fall back to what the epub crate read from it.
+pub fn get_metadata(book: &Book) -> BookMetadata {
+    let mut doc = EpubDoc::new(&book.epub).expect("Can't open epub");
+    let package = read_package(&mut doc);
+    let elements = &package.elements;
+
+    let refinements = |element: &MetaElement| -> Vec<&MetaElement> {
+        match element.attr("id") {
+            Some(id) => {
+                let target = format!("#{}", id);
+                elements
+                    .iter()
+                    .filter(|e| e.attr("refines") == Some(target.as_str()))
+                    .collect()
+            }
+            None => vec![],
+        }
+    };
+    let dc = |name: &str| -> Vec<&MetaElement> {
+        elements
+            .iter()
+            .filter(|e| e.name == name && !e.text.is_empty())
+            .collect()
+    };
+
+    let mut metadata = BookMetadata {
+        info_url: book.info_url.clone(),
+        base_url: book.base_url.clone(),
+        ..Default::default()
+    };
+
+    // prefer the title refined as `main`, otherwise the first one
+    let titles = dc("title");
+    metadata.title = titles
+        .iter()
+        .find(|t| {
+            refinements(t)
+                .iter()
+                .any(|r| r.attr("property") == Some("title-type") && r.text == "main")
+        })
+        .or_else(|| titles.first())
+        .map(|t| t.text.clone())
+        .or_else(|| doc.mdata("title"))
+        .unwrap_or_default();
+
+    metadata.languages = dc("language").iter().map(|l| l.text.clone()).collect();
+    if metadata.languages.is_empty() {
+        metadata.languages.extend(doc.mdata("language"));
+    }
+    if !book.language.is_empty() {
+        // the batch knows better than EPUBs shipped with the wrong dc:language
+        metadata.languages.retain(|l| l != &book.language);
+        metadata.languages.insert(0, book.language.clone());
+    }
+    metadata.language = metadata.languages.first().cloned().unwrap_or_default();
+    metadata.direction = text_direction(&metadata.language).to_string();
+    metadata.date = dc("date")
+        .first()
+        .map(|d| d.text.clone())
+        .or_else(|| doc.mdata("date"))
+        .unwrap_or_default();
+    metadata.publisher = dc("publisher")
+        .first()
+        .map(|p| p.text.clone())
+        .or_else(|| doc.mdata("publisher"))
+        .unwrap_or_default();
+    metadata.rights = dc("rights")
+        .first()
+        .map(|r| r.text.clone())
+        .or_else(|| doc.mdata("rights"))
+        .unwrap_or_default();
+    metadata.subjects = dc("subject").iter().map(|s| s.text.clone()).collect();
+    metadata.modified = first_meta(elements, "dcterms:modified");
+
+    metadata.description = if book.description.is_empty() {
+        dc("description")
+            .first()
+            .map(|d| d.text.clone())
+            .or_else(|| doc.mdata("description"))
+            .unwrap_or_default()
+    } else {
+        book.description.clone()
+    };
+
+    for element in dc("identifier") {
+        let (scheme, value) = identifier_scheme(element, &refinements(element));
+        if element.attr("id") == Some(package.unique_identifier.as_str()) {
+            metadata.unique_identifier = value.clone();
+        }
+        if scheme == "isbn" && metadata.isbn.is_empty() {
+            metadata.isbn = structured_data::isbn_from_identifier(&value).unwrap_or_default();
+        }
+        if scheme == "uuid" && metadata.uuid.is_empty() {
+            metadata.uuid = value.trim_start_matches("urn:uuid:").to_string();
+        }
+        metadata.identifiers.push(Identifier { scheme, value });
+    }
+    if metadata.unique_identifier.is_empty() {
+        metadata.unique_identifier = metadata
+            .identifiers
+            .first()
+            .map(|i| i.value.clone())
+            .or_else(|| doc.mdata("identifier"))
+            .unwrap_or_default();
+    }
+
+    for element in dc("creator") {
+        let c = contributor(element, &refinements(element), "aut");
+        if c.role == "aut" {
+            metadata.creators.push(c);
+        } else {
+            metadata.contributors.push(c);
+        }
+    }
+    for element in dc("contributor") {
+        let c = contributor(element, &refinements(element), "ctb");
+        metadata.contributors.push(c);
+    }
+    if metadata.creators.is_empty() {
+        if let Some(name) = doc.mdata("creator") {
+            metadata.creators.push(Contributor {
+                name,
+                role: "aut".to_string(),
+                role_name: role_name("aut").to_string(),
+                ..Default::default()
+            });
+        }
+    }
+    metadata.author = metadata
+        .creators
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    for element in elements.iter().filter(|e| {
+        e.attr("property") == Some("belongs-to-collection") && e.attr("refines").is_none()
+    }) {
+        let refined = refinements(element);
+        let find = |property: &str| {
+            refined
+                .iter()
+                .find(|r| r.attr("property") == Some(property))
+                .map(|r| r.text.clone())
+                .unwrap_or_default()
+        };
+        metadata.collections.push(Collection {
+            name: element.text.clone(),
+            kind: find("collection-type"),
+            position: find("group-position"),
+        });
+    }
+    let calibre_series = first_meta(elements, "calibre:series");
+    if !calibre_series.is_empty() {
+        metadata.collections.push(Collection {
+            name: calibre_series,
+            kind: "series".to_string(),
+            position: first_meta(elements, "calibre:series_index"),
+        });
+    }
+    metadata.series = metadata
+        .collections
+        .iter()
+        .find(|c| c.kind == "series")
+        .or_else(|| metadata.collections.first())
+        .cloned();
+
+    let conforms_to_links = elements
+        .iter()
+        .filter(|e| e.name == "link" && e.attr("rel") == Some("dcterms:conformsTo"))
+        .filter_map(|e| e.attr("href").map(|s| s.to_string()));
+    metadata.accessibility = Accessibility {
+        access_modes: meta_values(elements, "schema:accessMode"),
+        access_modes_sufficient: meta_values(elements, "schema:accessModeSufficient"),
+        features: meta_values(elements, "schema:accessibilityFeature"),
+        hazards: meta_values(elements, "schema:accessibilityHazard"),
+        summary: first_meta(elements, "schema:accessibilitySummary"),
+        conforms_to: meta_values(elements, "dcterms:conformsTo")
+            .into_iter()
+            .chain(conforms_to_links)
+            .collect(),
+    };
+
+    metadata.rendition = Rendition {
+        layout: first_meta(elements, "rendition:layout"),
+        spread: first_meta(elements, "rendition:spread"),
+        orientation: first_meta(elements, "rendition:orientation"),
+        direction: package.page_progression.clone(),
+    };
+    metadata.media_active_class = first_meta(elements, "media:active-class");
+
+    metadata
+}
+
+/// A template context with every metadata field at the top level (so
+/// `{{title}}` keeps working) and the whole struct under `metadata`.
+pub fn metadata_context(metadata: &BookMetadata) -> Context {
+    let mut ctx = Context::new();
+    if let Ok(Value::Object(fields)) = serde_json::to_value(metadata) {
+        for (key, val) in fields.iter() {
+            ctx.insert(key, val);
+        }
+    }
+    ctx.insert("metadata", metadata);
+    ctx.insert("lang", &metadata.language);
+    ctx.insert("dir", &metadata.direction);
+    ctx.insert("json_ld", &structured_data::book_json_ld(metadata));
+    ctx
+}
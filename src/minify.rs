@@ -0,0 +1,207 @@
+// Optional minification of the generated pages, book stylesheets and the
+// reader's static files.
+//
+// HTML goes through minify-html, which leaves `<pre>` and `<textarea>`
+// alone and only collapses whitespace between inline elements, never
+// removes it. It can't be told to keep whitespace elsewhere, so pages
+// styled with `white-space: pre` and the like aren't minified at all.
+// Stylesheets go through lightningcss and scripts through minify-js.
+// Anything a minifier can't parse is written as it was.
+
+use lightningcss::stylesheet::{MinifyOptions, ParserOptions, PrinterOptions, StyleSheet};
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+/// Sizes of the minified files of a book, for the batch report.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MinifyReport {
+    pub files: usize,
+    pub original_bytes: usize,
+    pub minified_bytes: usize,
+    pub saved_bytes: usize,
+}
+
+pub struct Minifier {
+    enabled: bool,
+    /// The book's stylesheets keep whitespace somewhere.
+    keep_whitespace: bool,
+    report: RefCell<MinifyReport>,
+}
+
+/// Whether `text`, a stylesheet or a page, sets a `white-space` that keeps
+/// spaces or line breaks, which collapsing them would change.
+pub fn preserves_whitespace(text: &str) -> bool {
+    let text = text.to_ascii_lowercase();
+    text.match_indices("white-space").any(|(i, property)| {
+        let rest = &text[i + property.len()..];
+        let rest = rest.strip_prefix("-collapse").unwrap_or(rest);
+        match rest.trim_start().strip_prefix(':') {
+            Some(value) => {
+                let value = value.trim_start();
+                value.starts_with("pre") || value.starts_with("break-spaces")
+            }
+            None => false,
+        }
+    })
+}
+
+fn minify_css(css: &str) -> Result<String, String> {
+    let mut stylesheet =
+        StyleSheet::parse(css, ParserOptions::default()).map_err(|e| e.to_string())?;
+    stylesheet
+        .minify(MinifyOptions::default())
+        .map_err(|e| e.to_string())?;
+    let printer = PrinterOptions {
+        minify: true,
+        ..Default::default()
+    };
+    stylesheet
+        .to_css(printer)
+        .map(|output| output.code)
+        .map_err(|e| e.to_string())
+}
+
+fn minify_js(js: &str) -> Result<String, String> {
+    let session = minify_js::Session::new();
+    let mut output = vec![];
+    minify_js::minify(
+        &session,
+        minify_js::TopLevelMode::Global,
+        js.as_bytes(),
+        &mut output,
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    String::from_utf8(output).map_err(|e| e.to_string())
+}
+
+fn minify_html(html: &str) -> String {
+    let cfg = minify_html::Cfg {
+        // pages are XHTML-ish and get checked by `validate`, so keep them
+        // easy to read back
+        keep_closing_tags: true,
+        keep_html_and_head_opening_tags: true,
+        keep_spaces_between_attributes: true,
+        ensure_spec_compliant_unquoted_attribute_values: true,
+        do_not_minify_doctype: true,
+        minify_css: true,
+        minify_js: true,
+        ..minify_html::Cfg::default()
+    };
+    String::from_utf8_lossy(&minify_html::minify(html.as_bytes(), &cfg)).into_owned()
+}
+
+impl Minifier {
+    pub fn new(enabled: bool, keep_whitespace: bool) -> Minifier {
+        Minifier {
+            enabled,
+            keep_whitespace,
+            report: RefCell::new(MinifyReport::default()),
+        }
+    }
+
+    fn count(&self, original: usize, minified: usize) {
+        let mut report = self.report.borrow_mut();
+        report.files += 1;
+        report.original_bytes += original;
+        report.minified_bytes += minified;
+        report.saved_bytes = report.original_bytes.saturating_sub(report.minified_bytes);
+    }
+
+    // Runs `minify` when enabled, keeping the original if it fails or
+    // doesn't get any smaller.
+    fn apply<F: FnOnce(&str) -> Result<String, String>>(
+        &self,
+        name: &str,
+        content: String,
+        minify: F,
+    ) -> String {
+        if !self.enabled {
+            return content;
+        }
+        let minified = match minify(&content) {
+            Ok(minified) if minified.len() < content.len() => minified,
+            Ok(_) => content.clone(),
+            Err(e) => {
+                debug!("can't minify {}: {}", name, e);
+                content.clone()
+            }
+        };
+        self.count(content.len(), minified.len());
+        minified
+    }
+
+    pub fn html(&self, name: &str, html: String) -> String {
+        if self.keep_whitespace || preserves_whitespace(&html) {
+            debug!("not minifying {}, its whitespace is styled to be kept", name);
+            return html;
+        }
+        self.apply(name, html, |html| Ok(minify_html(html)))
+    }
+
+    pub fn css(&self, name: &str, css: String) -> String {
+        self.apply(name, css, minify_css)
+    }
+
+    pub fn js(&self, name: &str, js: String) -> String {
+        self.apply(name, js, minify_js)
+    }
+
+    /// Minifies the stylesheets and scripts in `folder` in place.
+    pub fn minify_folder(&self, folder: &Path) {
+        if !self.enabled {
+            return;
+        }
+        let entries = match fs::read_dir(folder) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+            if ext != "css" && ext != "js" {
+                continue;
+            }
+            let content = match fs::read_to_string(&path) {
+                Ok(content) => content,
+                Err(_) => continue,
+            };
+            let name = path.to_string_lossy().into_owned();
+            let minified = if ext == "css" {
+                self.css(&name, content)
+            } else {
+                self.js(&name, content)
+            };
+            fs::write(&path, minified).unwrap_or_else(|e| panic!("Can't write {}: {}", name, e));
+        }
+    }
+
+    /// What was saved, if minification is on.
+    pub fn report(&self) -> Option<MinifyReport> {
+        if self.enabled {
+            Some(self.report.borrow().clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::preserves_whitespace;
+
+    #[test]
+    fn finds_whitespace_that_is_kept() {
+        assert!(preserves_whitespace(".poem { white-space: pre-wrap }"));
+        assert!(preserves_whitespace("<p style=\"WHITE-SPACE:pre\">"));
+        assert!(preserves_whitespace("p { white-space-collapse: preserve }"));
+        assert!(preserves_whitespace("p { white-space : break-spaces }"));
+    }
+
+    #[test]
+    fn ignores_whitespace_that_collapses() {
+        assert!(!preserves_whitespace("p { white-space: nowrap }"));
+        assert!(!preserves_whitespace("p { white-space: normal }"));
+    }
+}
@@ -0,0 +1,123 @@
+// Footnotes and endnotes as popovers.
+//
+// EPUB3 marks note references with `epub:type="noteref"` and the notes
+// themselves with `footnote`, `endnote` or `note`, often in a separate
+// endnotes chapter. All notes of a book are collected before any chapter
+// is rendered, then every noteref gets a copy of its note in an `<aside>`
+// that app.js shows next to the link. The link keeps pointing at the
+// original note, so without JavaScript it still jumps there.
+
+use scraper::{ElementRef, Html, Selector};
+use std::collections::HashMap;
+
+/// Note contents keyed by `filename#id`, with the output file names.
+pub type NotesMap = HashMap<String, String>;
+
+fn has_epub_type(types: Option<&str>, wanted: &[&str]) -> bool {
+    types
+        .map(|t| t.split_whitespace().any(|t| wanted.contains(&t)))
+        .unwrap_or(false)
+}
+
+/// Adds the notes found in one chapter to `notes`. `html` should already
+/// have its links pointing at the output files.
+pub fn collect_notes(filename: &str, html: &str, notes: &mut NotesMap) {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse("[id]").unwrap();
+    for element in document.select(&selector) {
+        let value = element.value();
+        if !has_epub_type(
+            value.attr("epub:type"),
+            &["footnote", "endnote", "note", "rearnote"],
+        ) {
+            continue;
+        }
+        let id = value.attr("id").unwrap_or_default();
+        // the copy must not repeat ids that are on the page already
+        let content = element
+            .inner_html()
+            .trim()
+            .replace(" id=\"", " data-note-id=\"");
+        notes.insert(format!("{}#{}", filename, id), content);
+    }
+}
+
+fn escape_attribute(s: &str) -> String {
+    s.replace('&', "&amp;").replace('"', "&quot;")
+}
+
+/// Gives every noteref in a chapter body its popover, returning the
+/// body's content. The noterefs are found in it by their own
+/// serialization, which only matches within the same parsed tree:
+/// scraper writes attributes in no fixed order.
+pub fn attach_popovers(filename: &str, body: &ElementRef, notes: &NotesMap) -> String {
+    let content = body.inner_html();
+    let content = content.as_str();
+    let selector = Selector::parse("a[href]").unwrap();
+
+    let mut result = String::with_capacity(content.len());
+    let mut rest = content;
+    let mut asides = String::new();
+    let mut count = 0;
+    for link in body.select(&selector) {
+        let value = link.value();
+        if !has_epub_type(value.attr("epub:type"), &["noteref"]) {
+            continue;
+        }
+        let href = value.attr("href").unwrap_or_default();
+        let key = if href.starts_with('#') {
+            format!("{}{}", filename, href)
+        } else {
+            href.to_string()
+        };
+        let note = match notes.get(&key) {
+            Some(note) => note,
+            None => continue,
+        };
+
+        let original = link.html();
+        let position = match rest.find(&original) {
+            Some(position) => position,
+            None => continue,
+        };
+        count += 1;
+        let popover_id = format!("note-popover-{}", count);
+        let noteref_id = match value.attr("id") {
+            Some(id) => id.to_string(),
+            None => format!("noteref-{}", count),
+        };
+        let mut attributes = format!(
+            " data-note=\"{}\" aria-controls=\"{}\" aria-expanded=\"false\"",
+            popover_id, popover_id
+        );
+        if value.attr("id").is_none() {
+            attributes = format!(" id=\"{}\"{}", noteref_id, attributes);
+        }
+
+        result.push_str(&rest[..position]);
+        result.push_str("<a");
+        result.push_str(&attributes);
+        result.push_str(&original["<a".len()..]);
+        rest = &rest[position + original.len()..];
+
+        asides.push_str(&format!(
+            "<aside id=\"{}\" class=\"note-popover\" role=\"doc-footnote\" hidden>\
+             <div class=\"note-popover-content\">{}</div>\
+             <a class=\"note-popover-back\" href=\"#{}\">Back to text</a> \
+             <a class=\"note-popover-source\" href=\"{}\">Go to note</a>\
+             </aside>",
+            popover_id,
+            note,
+            escape_attribute(&noteref_id),
+            escape_attribute(href)
+        ));
+    }
+    result.push_str(rest);
+
+    if !asides.is_empty() {
+        result.push_str("<div class=\"note-popovers\">");
+        result.push_str(&asides);
+        result.push_str("</div>");
+    }
+    result
+}
@@ -0,0 +1,564 @@
+// OPDS catalogs for a batch job.
+//
+// Writes an OPDS 1.2 Atom catalog and an OPDS 2.0 JSON feed side by side,
+// each with a root navigation feed, an "all books" acquisition feed and
+// navigation feeds grouped by language and by series.
+
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use scraper::Html;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent};
+
+use super::metadata::{get_metadata, BookMetadata, Contributor};
+use super::Book;
+
+const ATOM_NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ATOM_ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPDS2_TYPE: &str = "application/opds+json";
+const ACQUISITION_REL: &str = "http://opds-spec.org/acquisition/open-access";
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct OpdsConfig {
+    pub output_folder: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub base_url: String,
+}
+
+struct Publication {
+    id: String,
+    summary: String,
+    series: String,
+    updated: String,
+    info_url: String,
+    pwa_url: String,
+    epub_url: String,
+    cover: Option<String>,
+    thumbnail: Option<String>,
+    metadata: BookMetadata,
+}
+
+struct NavigationEntry {
+    id: String,
+    title: String,
+    slug: String,
+    acquisition: bool,
+}
+
+fn slugify(s: &str) -> String {
+    let slug: String = s
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+    slug.split('-')
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn strip_tags(s: &str) -> String {
+    let fragment = Html::parse_fragment(s);
+    let text: String = fragment.root_element().text().collect();
+    text.trim().to_string()
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// OPF dates are anything from a bare year to a full timestamp, Atom wants RFC 3339.
+fn atom_date(s: &str) -> String {
+    let s = s.trim();
+    if let Ok(d) = DateTime::parse_from_rfc3339(s) {
+        return d
+            .with_timezone(&Utc)
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(&format!("{}-01", s), "%Y-%m-%d"))
+        .or_else(|_| NaiveDate::parse_from_str(&format!("{}-01-01", s), "%Y-%m-%d"));
+    match date {
+        Ok(d) => format!("{}T00:00:00Z", d),
+        Err(_) => now(),
+    }
+}
+
+fn epub_filename(book: &Book) -> String {
+    Path::new(&book.epub)
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Copies the epub next to the PWA when the book has no download location,
+/// for the feed to link to. Part of the book conversion, which clears the
+/// output folder first.
+pub fn ship_epub(book: &Book) {
+    if !book.epub_url.is_empty() {
+        return;
+    }
+    let target = Path::new(&book.output_folder).join(epub_filename(book));
+    if let Err(e) = fs::copy(&book.epub, &target) {
+        error!("Can't copy {} to {}: {}", book.epub, target.display(), e);
+    }
+}
+
+fn publication(book: &Book) -> Publication {
+    let metadata = get_metadata(book);
+    let book_root = Path::new(&book.output_folder);
+
+    let epub_url = if book.epub_url.is_empty() {
+        // shipped next to the PWA by ship_epub
+        format!("{}/{}", book.base_url, epub_filename(book))
+    } else {
+        book.epub_url.clone()
+    };
+
+    let id = if !metadata.unique_identifier.is_empty() {
+        metadata.unique_identifier.clone()
+    } else if !book.info_url.is_empty() {
+        book.info_url.clone()
+    } else {
+        format!("urn:epub2pwa:{}", slugify(&book.output_folder))
+    };
+
+    let updated = if !metadata.modified.is_empty() {
+        atom_date(&metadata.modified)
+    } else {
+        atom_date(&metadata.date)
+    };
+
+    let has_cover = book_root.join("cover.jpg").exists();
+
+    Publication {
+        id,
+        summary: strip_tags(&metadata.description),
+        series: metadata
+            .series
+            .as_ref()
+            .map(|s| s.name.clone())
+            .unwrap_or_default(),
+        updated,
+        info_url: book.info_url.clone(),
+        pwa_url: format!("{}/index.html", book.base_url),
+        epub_url,
+        cover: if has_cover {
+            Some(format!("{}/cover.jpg", book.base_url))
+        } else {
+            None
+        },
+        thumbnail: if has_cover {
+            Some(format!("{}/cover_resized.jpg", book.base_url))
+        } else {
+            None
+        },
+        metadata,
+    }
+}
+
+fn text_element(writer: &mut EventWriter<File>, name: &str, text: &str) {
+    writer.write(XmlEvent::start_element(name)).unwrap();
+    writer.write(XmlEvent::characters(text)).unwrap();
+    writer.write(XmlEvent::end_element()).unwrap();
+}
+
+fn link_element(writer: &mut EventWriter<File>, rel: &str, href: &str, kind: &str) {
+    writer
+        .write(
+            XmlEvent::start_element("link")
+                .attr("rel", rel)
+                .attr("href", href)
+                .attr("type", kind),
+        )
+        .unwrap();
+    writer.write(XmlEvent::end_element()).unwrap();
+}
+
+fn start_atom_feed(
+    config: &OpdsConfig,
+    path: &Path,
+    slug: &str,
+    title: &str,
+    kind: &str,
+) -> EventWriter<File> {
+    let file = File::create(path).expect("Can't create OPDS feed");
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(file);
+
+    writer
+        .write(
+            XmlEvent::start_element("feed")
+                .default_ns("http://www.w3.org/2005/Atom")
+                .ns("dc", "http://purl.org/dc/terms/")
+                .ns("opds", "http://opds-spec.org/2010/catalog"),
+        )
+        .unwrap();
+    text_element(
+        &mut writer,
+        "id",
+        &format!("{}/{}.xml", config.base_url, slug),
+    );
+    text_element(&mut writer, "title", title);
+    text_element(&mut writer, "updated", &now());
+    link_element(&mut writer, "self", &format!("{}.xml", slug), kind);
+    link_element(&mut writer, "start", "catalog.xml", ATOM_NAVIGATION);
+    writer
+}
+
+fn write_atom_acquisition(
+    config: &OpdsConfig,
+    slug: &str,
+    title: &str,
+    publications: &[&Publication],
+) {
+    let path = Path::new(&config.output_folder).join(format!("{}.xml", slug));
+    let mut writer = start_atom_feed(config, &path, slug, title, ATOM_ACQUISITION);
+
+    for p in publications {
+        writer.write(XmlEvent::start_element("entry")).unwrap();
+        let m = &p.metadata;
+        text_element(&mut writer, "title", &m.title);
+        text_element(&mut writer, "id", &p.id);
+        text_element(&mut writer, "updated", &p.updated);
+        for author in m.creators.iter() {
+            writer.write(XmlEvent::start_element("author")).unwrap();
+            text_element(&mut writer, "name", &author.name);
+            writer.write(XmlEvent::end_element()).unwrap();
+        }
+        for contributor in m.contributors.iter() {
+            writer
+                .write(XmlEvent::start_element("contributor"))
+                .unwrap();
+            text_element(&mut writer, "name", &contributor.name);
+            writer.write(XmlEvent::end_element()).unwrap();
+        }
+        for language in m.languages.iter() {
+            text_element(&mut writer, "dc:language", language);
+        }
+        if !m.publisher.is_empty() {
+            text_element(&mut writer, "dc:publisher", &m.publisher);
+        }
+        if !m.date.is_empty() {
+            text_element(&mut writer, "dc:issued", &m.date);
+        }
+        if !m.rights.is_empty() {
+            text_element(&mut writer, "rights", &m.rights);
+        }
+        for identifier in m.identifiers.iter() {
+            text_element(&mut writer, "dc:identifier", &identifier.value);
+        }
+        for subject in m.subjects.iter() {
+            writer
+                .write(
+                    XmlEvent::start_element("category")
+                        .attr("term", subject)
+                        .attr("label", subject),
+                )
+                .unwrap();
+            writer.write(XmlEvent::end_element()).unwrap();
+        }
+        if !p.summary.is_empty() {
+            writer
+                .write(XmlEvent::start_element("summary").attr("type", "text"))
+                .unwrap();
+            writer.write(XmlEvent::characters(&p.summary)).unwrap();
+            writer.write(XmlEvent::end_element()).unwrap();
+        }
+        if let Some(cover) = &p.cover {
+            link_element(
+                &mut writer,
+                "http://opds-spec.org/image",
+                cover,
+                "image/jpeg",
+            );
+        }
+        if let Some(thumbnail) = &p.thumbnail {
+            link_element(
+                &mut writer,
+                "http://opds-spec.org/image/thumbnail",
+                thumbnail,
+                "image/jpeg",
+            );
+        }
+        link_element(
+            &mut writer,
+            ACQUISITION_REL,
+            &p.epub_url,
+            "application/epub+zip",
+        );
+        link_element(&mut writer, ACQUISITION_REL, &p.pwa_url, "text/html");
+        if !p.info_url.is_empty() {
+            link_element(&mut writer, "alternate", &p.info_url, "text/html");
+        }
+        writer.write(XmlEvent::end_element()).unwrap();
+    }
+
+    writer.write(XmlEvent::end_element()).unwrap();
+}
+
+fn write_atom_navigation(
+    config: &OpdsConfig,
+    slug: &str,
+    title: &str,
+    entries: &[NavigationEntry],
+) {
+    let path = Path::new(&config.output_folder).join(format!("{}.xml", slug));
+    let mut writer = start_atom_feed(config, &path, slug, title, ATOM_NAVIGATION);
+    let updated = now();
+
+    for e in entries {
+        let kind = if e.acquisition {
+            ATOM_ACQUISITION
+        } else {
+            ATOM_NAVIGATION
+        };
+        writer.write(XmlEvent::start_element("entry")).unwrap();
+        text_element(&mut writer, "title", &e.title);
+        text_element(&mut writer, "id", &e.id);
+        text_element(&mut writer, "updated", &updated);
+        link_element(&mut writer, "subsection", &format!("{}.xml", e.slug), kind);
+        writer.write(XmlEvent::end_element()).unwrap();
+    }
+
+    writer.write(XmlEvent::end_element()).unwrap();
+}
+
+fn opds2_contributors(list: &[&Contributor]) -> Value {
+    Value::from(
+        list.iter()
+            .map(|c| {
+                if c.file_as.is_empty() {
+                    json!({ "name": c.name })
+                } else {
+                    json!({ "name": c.name, "sortAs": c.file_as })
+                }
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+fn opds2_publication(p: &Publication) -> Value {
+    let m = &p.metadata;
+    let mut metadata = json!({
+        "@type": "http://schema.org/Book",
+        "identifier": p.id,
+        "title": m.title,
+        "modified": p.updated,
+    });
+    if !m.creators.is_empty() {
+        metadata["author"] = opds2_contributors(&m.creators.iter().collect::<Vec<_>>());
+    }
+    // OPDS 2 has dedicated keys for the common MARC relator roles
+    for (role, key) in [
+        ("trl", "translator"),
+        ("ill", "illustrator"),
+        ("edt", "editor"),
+        ("nrt", "narrator"),
+    ]
+    .iter()
+    {
+        let people: Vec<&Contributor> = m.contributors.iter().filter(|c| c.role == *role).collect();
+        if !people.is_empty() {
+            metadata[*key] = opds2_contributors(&people);
+        }
+    }
+    if !m.languages.is_empty() {
+        metadata["language"] = json!(m.languages);
+    }
+    if !m.publisher.is_empty() {
+        metadata["publisher"] = json!(m.publisher);
+    }
+    if !m.date.is_empty() {
+        metadata["published"] = json!(m.date);
+    }
+    if !m.subjects.is_empty() {
+        metadata["subject"] = json!(m.subjects);
+    }
+    if !p.summary.is_empty() {
+        metadata["description"] = json!(p.summary);
+    }
+    if let Some(series) = &m.series {
+        let position: Option<f64> = series.position.parse().ok();
+        metadata["belongsTo"] = match position {
+            Some(position) => json!({ "series": { "name": series.name, "position": position } }),
+            None => json!({ "series": { "name": series.name } }),
+        };
+    }
+
+    let mut links = vec![
+        json!({ "rel": ACQUISITION_REL, "href": p.epub_url, "type": "application/epub+zip" }),
+        json!({ "rel": ACQUISITION_REL, "href": p.pwa_url, "type": "text/html" }),
+    ];
+    if !p.info_url.is_empty() {
+        links.push(json!({ "rel": "alternate", "href": p.info_url, "type": "text/html" }));
+    }
+
+    let mut images = vec![];
+    if let Some(cover) = &p.cover {
+        images.push(json!({ "href": cover, "type": "image/jpeg" }));
+    }
+    if let Some(thumbnail) = &p.thumbnail {
+        images.push(json!({ "href": thumbnail, "type": "image/jpeg", "width": super::ICON_WIDTH }));
+    }
+
+    json!({ "metadata": metadata, "links": links, "images": images })
+}
+
+fn write_opds2(config: &OpdsConfig, slug: &str, feed: Value) {
+    let path = Path::new(&config.output_folder).join(format!("{}.json", slug));
+    let j = serde_json::to_string_pretty(&feed).expect("Can't serialize OPDS 2 feed");
+    fs::write(path, &j).expect("Can't write OPDS 2 feed");
+}
+
+fn opds2_links(slug: &str) -> Value {
+    json!([
+        { "rel": "self", "href": format!("{}.json", slug), "type": OPDS2_TYPE },
+        { "rel": "start", "href": "catalog.json", "type": OPDS2_TYPE },
+    ])
+}
+
+fn write_opds2_acquisition(
+    config: &OpdsConfig,
+    slug: &str,
+    title: &str,
+    publications: &[&Publication],
+) {
+    let feed = json!({
+        "metadata": { "title": title, "numberOfItems": publications.len() },
+        "links": opds2_links(slug),
+        "publications": publications.iter().map(|p| opds2_publication(p)).collect::<Vec<_>>(),
+    });
+    write_opds2(config, slug, feed);
+}
+
+fn write_opds2_navigation(
+    config: &OpdsConfig,
+    slug: &str,
+    title: &str,
+    entries: &[NavigationEntry],
+) {
+    let navigation: Vec<Value> = entries
+        .iter()
+        .map(
+            |e| json!({ "title": e.title, "href": format!("{}.json", e.slug), "type": OPDS2_TYPE }),
+        )
+        .collect();
+    let feed = json!({
+        "metadata": { "title": title },
+        "links": opds2_links(slug),
+        "navigation": navigation,
+    });
+    write_opds2(config, slug, feed);
+}
+
+fn write_acquisition(config: &OpdsConfig, slug: &str, title: &str, publications: &[&Publication]) {
+    write_atom_acquisition(config, slug, title, publications);
+    write_opds2_acquisition(config, slug, title, publications);
+}
+
+fn write_navigation(config: &OpdsConfig, slug: &str, title: &str, entries: &[NavigationEntry]) {
+    write_atom_navigation(config, slug, title, entries);
+    write_opds2_navigation(config, slug, title, entries);
+}
+
+/// Writes the OPDS 1.2 and 2.0 catalogs for every successfully converted
+/// book, returning the files written.
+pub fn process_opds(config: &OpdsConfig, books: &[Book]) -> Vec<PathBuf> {
+    let output_root = Path::new(&config.output_folder);
+    let _resp = fs::create_dir_all(output_root);
+    info!("Building OPDS catalog in {}", &config.output_folder);
+
+    let publications: Vec<Publication> = books
+        .iter()
+        .filter(|b| b.status == "success" && Path::new(&b.epub).exists())
+        .map(publication)
+        .collect();
+
+    let mut by_language: BTreeMap<&str, Vec<&Publication>> = BTreeMap::new();
+    let mut by_series: BTreeMap<&str, Vec<&Publication>> = BTreeMap::new();
+    for p in publications.iter() {
+        if !p.metadata.language.is_empty() {
+            by_language.entry(&p.metadata.language).or_default().push(p);
+        }
+        if !p.series.is_empty() {
+            by_series.entry(&p.series).or_default().push(p);
+        }
+    }
+
+    let all: Vec<&Publication> = publications.iter().collect();
+    write_acquisition(config, "all", "All books", &all);
+
+    let mut language_entries = vec![];
+    for (language, list) in by_language.iter() {
+        let slug = format!("language-{}", slugify(language));
+        write_acquisition(config, &slug, language, list);
+        language_entries.push(NavigationEntry {
+            id: format!("{}/{}.xml", config.base_url, slug),
+            title: language.to_string(),
+            slug,
+            acquisition: true,
+        });
+    }
+    write_navigation(config, "languages", "By language", &language_entries);
+
+    let mut series_entries = vec![];
+    for (series, list) in by_series.iter() {
+        let slug = format!("series-{}", slugify(series));
+        write_acquisition(config, &slug, series, list);
+        series_entries.push(NavigationEntry {
+            id: format!("{}/{}.xml", config.base_url, slug),
+            title: series.to_string(),
+            slug,
+            acquisition: true,
+        });
+    }
+    write_navigation(config, "series", "By series", &series_entries);
+
+    let root = vec![
+        NavigationEntry {
+            id: format!("{}/all.xml", config.base_url),
+            title: "All books".to_string(),
+            slug: "all".to_string(),
+            acquisition: true,
+        },
+        NavigationEntry {
+            id: format!("{}/languages.xml", config.base_url),
+            title: "By language".to_string(),
+            slug: "languages".to_string(),
+            acquisition: false,
+        },
+        NavigationEntry {
+            id: format!("{}/series.xml", config.base_url),
+            title: "By series".to_string(),
+            slug: "series".to_string(),
+            acquisition: false,
+        },
+    ];
+    write_navigation(config, "catalog", &config.title, &root);
+
+    info!("OPDS catalog lists {} books", publications.len());
+
+    // every feed is written as `slug.xml` and `slug.json`
+    let slugs = ["all", "languages", "series", "catalog"]
+        .iter()
+        .map(|s| s.to_string())
+        .chain(language_entries.iter().map(|e| e.slug.clone()))
+        .chain(series_entries.iter().map(|e| e.slug.clone()));
+    slugs
+        .flat_map(|slug| {
+            vec![
+                output_root.join(format!("{}.xml", slug)),
+                output_root.join(format!("{}.json", slug)),
+            ]
+        })
+        .collect()
+}
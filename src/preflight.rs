@@ -0,0 +1,513 @@
+// `preflight`: conformance checks on an ePub before converting it.
+//
+// Most of our books come out of InDesign, and the quirks we keep running
+// into are checked here: a missing or misplaced `mimetype`, container.xml
+// pointing at the wrong OPF, manifest entries without a file (or files
+// without a manifest entry), spine items that aren't in the manifest,
+// media types that don't match the file, XHTML that doesn't parse as XML
+// and covers the converter can't find. Errors are problems the conversion
+// will fail on, warnings are things it will work around or get wrong.
+
+use epub::archive::EpubArchive;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent};
+
+use super::fonts::{is_obfuscation, parse_encryption};
+use super::metadata::{parse_package, Package};
+use super::css::percent_decode;
+use super::validate::Severity;
+use super::xhtml;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    pub category: String,
+    pub file: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PreflightReport {
+    pub errors: usize,
+    pub warnings: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl PreflightReport {
+    fn add(&mut self, severity: Severity, category: &str, file: &str, message: String) {
+        match severity {
+            Severity::Error => self.errors += 1,
+            Severity::Warning => self.warnings += 1,
+        }
+        self.issues.push(Issue {
+            severity,
+            category: category.to_string(),
+            file: file.to_string(),
+            message,
+        });
+    }
+
+    fn error(&mut self, category: &str, file: &str, message: String) {
+        self.add(Severity::Error, category, file, message);
+    }
+
+    fn warning(&mut self, category: &str, file: &str, message: String) {
+        self.add(Severity::Warning, category, file, message);
+    }
+}
+
+// The first XML error in `data`, with its position. The named entities
+// of the XHTML DTDs are known, the converter reads them too.
+fn xml_error(data: &[u8]) -> Option<String> {
+    for event in xhtml::parser_config().create_reader(data) {
+        match event {
+            Ok(XmlEvent::EndDocument) => return None,
+            Err(e) => return Some(e.to_string()),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Joins a manifest href onto the OPF folder, the way zip entry names
+// are written.
+fn resolve_href(base: &str, href: &str) -> String {
+    let href = percent_decode(href.split('#').next().unwrap_or_default());
+    let mut parts: Vec<&str> = base.split('/').filter(|p| !p.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn expected_media_types(path: &str) -> &'static [&'static str] {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match ext.as_str() {
+        "xhtml" | "html" | "htm" => &["application/xhtml+xml", "text/html"],
+        "css" => &["text/css"],
+        "ncx" => &["application/x-dtbncx+xml"],
+        "jpg" | "jpeg" => &["image/jpeg"],
+        "png" => &["image/png"],
+        "gif" => &["image/gif"],
+        "svg" => &["image/svg+xml"],
+        "webp" => &["image/webp"],
+        "otf" => &[
+            "font/otf",
+            "application/vnd.ms-opentype",
+            "application/font-sfnt",
+            "application/x-font-otf",
+        ],
+        "ttf" => &[
+            "font/ttf",
+            "application/font-sfnt",
+            "application/x-font-ttf",
+            "application/x-font-truetype",
+        ],
+        "woff" => &["font/woff", "application/font-woff"],
+        "woff2" => &["font/woff2"],
+        "js" => &["application/javascript", "text/javascript"],
+        "smil" => &["application/smil+xml"],
+        "mp3" => &["audio/mpeg"],
+        "mp4" | "m4a" => &["audio/mp4", "video/mp4"],
+        _ => &[],
+    }
+}
+
+// Media type from the first bytes, for the formats we can tell apart.
+fn sniff_media_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() > 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn check_mimetype(epub: &str, report: &mut PreflightReport) {
+    let file = match File::open(epub) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    let mut zip = match zip::ZipArchive::new(file) {
+        Ok(zip) => zip,
+        Err(_) => return,
+    };
+    match zip.by_index(0) {
+        Ok(mut first) if first.name() == "mimetype" => {
+            if first.compression() != zip::CompressionMethod::Stored {
+                report.warning(
+                    "container",
+                    "mimetype",
+                    "mimetype is compressed, it must be stored".to_string(),
+                );
+            }
+            let mut content = String::new();
+            let _resp = first.read_to_string(&mut content);
+            if content.trim() != "application/epub+zip" {
+                report.warning(
+                    "container",
+                    "mimetype",
+                    format!(
+                        "mimetype is \"{}\", not application/epub+zip",
+                        content.trim()
+                    ),
+                );
+            }
+        }
+        Ok(first) => report.warning(
+            "container",
+            first.name(),
+            "mimetype is not the first file in the archive".to_string(),
+        ),
+        Err(e) => report.error("container", "", format!("can't read archive: {}", e)),
+    };
+}
+
+// `full-path` of the first OEBPS rootfile in container.xml.
+fn container_root_file(container: &[u8]) -> Option<String> {
+    for event in EventReader::new(container) {
+        if let Ok(XmlEvent::StartElement {
+            name, attributes, ..
+        }) = event
+        {
+            if name.local_name == "rootfile" {
+                let media_type = attributes
+                    .iter()
+                    .find(|a| a.name.local_name == "media-type")
+                    .map(|a| a.value.as_str());
+                if media_type.is_none() || media_type == Some("application/oebps-package+xml") {
+                    return attributes
+                        .iter()
+                        .find(|a| a.name.local_name == "full-path")
+                        .map(|a| a.value.clone());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn check_package(package: &Package, opf_path: &str, report: &mut PreflightReport) {
+    for (element, what) in [
+        ("title", "dc:title"),
+        ("language", "dc:language"),
+        ("identifier", "dc:identifier"),
+    ]
+    .iter()
+    {
+        if !package.has_element(element) {
+            report.warning(
+                "package",
+                opf_path,
+                format!("no {}, the converter will leave it empty", what),
+            );
+        }
+    }
+    if package.manifest.is_empty() {
+        report.error("manifest", opf_path, "manifest is empty".to_string());
+    }
+    if package.spine.is_empty() {
+        report.error("spine", opf_path, "spine is empty".to_string());
+    }
+}
+
+fn check_manifest(
+    archive: &mut EpubArchive<::std::io::BufReader<File>>,
+    package: &Package,
+    opf_path: &str,
+    report: &mut PreflightReport,
+) {
+    let base = match opf_path.rfind('/') {
+        Some(i) => &opf_path[..i],
+        None => "",
+    };
+    let files: HashSet<String> = archive.files.iter().cloned().collect();
+    let spine_ids: HashSet<&str> = package.spine.iter().map(|s| s.idref.as_str()).collect();
+
+    let mut ids = HashMap::new();
+    let mut listed = HashSet::new();
+    for item in package.manifest.iter() {
+        let path = resolve_href(base, &item.href);
+        if ids.insert(item.id.as_str(), path.clone()).is_some() {
+            report.warning(
+                "manifest",
+                &path,
+                format!("duplicate manifest id \"{}\"", item.id),
+            );
+        }
+        if !listed.insert(path.clone()) {
+            report.warning(
+                "manifest",
+                &path,
+                "listed twice in the manifest".to_string(),
+            );
+        }
+
+        if !files.contains(&path) {
+            if spine_ids.contains(item.id.as_str()) {
+                report.error(
+                    "files",
+                    &path,
+                    format!("spine item \"{}\" is missing from the archive", item.id),
+                );
+            } else {
+                report.warning(
+                    "files",
+                    &path,
+                    format!("manifest item \"{}\" is missing from the archive", item.id),
+                );
+            }
+            continue;
+        }
+
+        let data = match archive.get_entry(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                report.error("files", &path, format!("can't read: {}", e));
+                continue;
+            }
+        };
+
+        // one finding per file: what the magic bytes say beats the extension
+        let sniffed = sniff_media_type(&data).filter(|_| item.media_type.starts_with("image/"));
+        let expected = expected_media_types(&path);
+        if item.media_type.is_empty() {
+            report.warning("mime", &path, "manifest item has no media-type".to_string());
+        } else if let Some(sniffed) = sniffed {
+            if item.media_type != sniffed {
+                report.warning(
+                    "mime",
+                    &path,
+                    format!(
+                        "declared as {} but the file is {}",
+                        item.media_type, sniffed
+                    ),
+                );
+            }
+        } else if !expected.is_empty() && !expected.contains(&item.media_type.as_str()) {
+            report.warning(
+                "mime",
+                &path,
+                format!(
+                    "declared as {}, expected {}",
+                    item.media_type,
+                    expected.join(" or ")
+                ),
+            );
+        }
+        if item.media_type == "application/xhtml+xml" {
+            if let Some(e) = xml_error(&data) {
+                report.warning("xhtml", &path, format!("not well-formed XML: {}", e));
+            }
+        }
+    }
+
+    let mut unlisted: Vec<&String> = files
+        .iter()
+        .filter(|f| !f.ends_with('/') && !listed.contains(*f))
+        .filter(|f| *f != "mimetype" && !f.starts_with("META-INF/") && *f != opf_path)
+        .collect();
+    unlisted.sort();
+    for file in unlisted {
+        report.warning(
+            "files",
+            file,
+            "not listed in the manifest, it won't be converted".to_string(),
+        );
+    }
+
+    for (i, item) in package.spine.iter().enumerate() {
+        let manifest_item = package.manifest.iter().find(|m| m.id == item.idref);
+        match manifest_item {
+            None => report.error(
+                "spine",
+                opf_path,
+                format!(
+                    "spine item {} (\"{}\") is not in the manifest",
+                    i + 1,
+                    item.idref
+                ),
+            ),
+            Some(m) if !m.media_type.contains("html") => report.warning(
+                "spine",
+                &ids[m.id.as_str()],
+                format!(
+                    "spine item {} is {}, only HTML is converted",
+                    i + 1,
+                    m.media_type
+                ),
+            ),
+            _ => {}
+        }
+    }
+    if !package.spine_toc.is_empty() && !ids.contains_key(package.spine_toc.as_str()) {
+        report.warning(
+            "spine",
+            opf_path,
+            format!("spine toc \"{}\" is not in the manifest", package.spine_toc),
+        );
+    }
+
+    let cover_id = package.cover_id();
+    let cover_image = package
+        .manifest
+        .iter()
+        .find(|m| m.properties.iter().any(|p| p == "cover-image"));
+    if cover_id.is_empty() {
+        let hint = match cover_image {
+            Some(m) => format!(
+                ", add <meta name=\"cover\" content=\"{}\"/> for the cover-image item",
+                m.id
+            ),
+            None => String::new(),
+        };
+        report.warning(
+            "cover",
+            opf_path,
+            format!(
+                "no <meta name=\"cover\">, the book will have no cover{}",
+                hint
+            ),
+        );
+    } else if !ids.contains_key(cover_id.as_str()) {
+        report.warning(
+            "cover",
+            opf_path,
+            format!("cover id \"{}\" is not in the manifest", cover_id),
+        );
+    }
+}
+
+// Obfuscated fonts are fine, anything else in encryption.xml is DRM. The
+// book still converts, with those files broken, as `process_book` warns.
+fn check_encryption(
+    archive: &mut EpubArchive<::std::io::BufReader<File>>,
+    report: &mut PreflightReport,
+) {
+    let xml = match archive.get_entry("META-INF/encryption.xml") {
+        Ok(xml) => xml,
+        Err(_) => return,
+    };
+    let mut files: Vec<(String, String)> = parse_encryption(&xml).into_iter().collect();
+    files.sort();
+    for (path, algorithm) in files {
+        if !is_obfuscation(&algorithm) {
+            report.warning(
+                "encryption",
+                &path,
+                format!(
+                    "encrypted with {} (DRM), can't be converted, its output will be broken",
+                    algorithm
+                ),
+            );
+        }
+    }
+}
+
+/// Runs all checks on `epub`. Problems are never an `Err`, they are
+/// issues in the report.
+pub fn preflight(epub: &str) -> PreflightReport {
+    let mut report = PreflightReport::default();
+    let mut archive = match EpubArchive::new(epub) {
+        Ok(archive) => archive,
+        Err(e) => {
+            report.error("container", epub, format!("can't open as zip: {}", e));
+            return report;
+        }
+    };
+    check_mimetype(epub, &mut report);
+
+    let container = match archive.get_container_file() {
+        Ok(container) => container,
+        Err(_) => {
+            report.error(
+                "container",
+                "META-INF/container.xml",
+                "META-INF/container.xml is missing".to_string(),
+            );
+            return report;
+        }
+    };
+    if let Some(e) = xml_error(&container) {
+        report.error(
+            "container",
+            "META-INF/container.xml",
+            format!("not well-formed XML: {}", e),
+        );
+        return report;
+    }
+    let opf_path = match container_root_file(&container) {
+        Some(path) => path,
+        None => {
+            report.error(
+                "container",
+                "META-INF/container.xml",
+                "no rootfile with a full-path".to_string(),
+            );
+            return report;
+        }
+    };
+
+    let opf = match archive.get_entry(&opf_path) {
+        Ok(opf) => opf,
+        Err(_) => {
+            report.error(
+                "package",
+                &opf_path,
+                "the OPF named in container.xml is missing".to_string(),
+            );
+            return report;
+        }
+    };
+    if let Some(e) = xml_error(&opf) {
+        report.error("package", &opf_path, format!("not well-formed XML: {}", e));
+        return report;
+    }
+
+    let package = parse_package(&opf);
+    check_package(&package, &opf_path, &mut report);
+    check_manifest(&mut archive, &package, &opf_path, &mut report);
+    check_encryption(&mut archive, &mut report);
+    report
+}
+
+/// Logs a report, grouped by category.
+pub fn log_report(epub: &str, report: &PreflightReport) {
+    let mut categories: BTreeMap<&str, Vec<&Issue>> = BTreeMap::new();
+    for issue in report.issues.iter() {
+        categories
+            .entry(issue.category.as_str())
+            .or_default()
+            .push(issue);
+    }
+    for (category, issues) in categories {
+        for issue in issues {
+            match issue.severity {
+                Severity::Error => error!("[{}] {}: {}", category, issue.file, issue.message),
+                Severity::Warning => warn!("[{}] {}: {}", category, issue.file, issue.message),
+            }
+        }
+    }
+    info!(
+        "{}: {} errors, {} warnings",
+        epub, report.errors, report.warnings
+    );
+}
@@ -0,0 +1,171 @@
+// A small static file server for previewing an output folder.
+//
+// Service workers only register on https or localhost, so opening the
+// generated pages from disk doesn't show how the PWA behaves. This serves
+// the folder on localhost with the content types the reader needs. It is
+// meant for checking a build, not for hosting.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+
+use super::css::percent_decode;
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "xhtml" => "application/xhtml+xml",
+        "css" => "text/css; charset=utf-8",
+        "js" => "application/javascript; charset=utf-8",
+        "json" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "xml" | "opf" | "ncx" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "epub" => "application/epub+zip",
+        "csv" => "text/csv; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+// Maps a request target onto a file in `root`, refusing anything that
+// would climb out of it.
+fn resolve(root: &Path, target: &str) -> Option<PathBuf> {
+    let path = target.split(['?', '#']).next().unwrap_or("/");
+    let path = percent_decode(path);
+    let mut file = root.to_path_buf();
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => file.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    Some(file)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, kind: &str, body: &[u8], head_only: bool) {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status,
+        kind,
+        body.len()
+    );
+    let _resp = stream.write_all(header.as_bytes());
+    if !head_only {
+        let _resp = stream.write_all(body);
+    }
+}
+
+fn handle(root: &Path, mut stream: TcpStream) {
+    let mut request_line = String::new();
+    {
+        let mut reader = BufReader::new(&stream);
+        if reader.read_line(&mut request_line).is_err() {
+            return;
+        }
+        // drain the headers, nothing in them matters here
+        let mut line = String::new();
+        while reader.read_line(&mut line).map(|n| n > 2).unwrap_or(false) {
+            line.clear();
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or("/");
+    if method != "GET" && method != "HEAD" {
+        respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"",
+            false,
+        );
+        return;
+    }
+    let head_only = method == "HEAD";
+
+    match resolve(root, target).map(|file| (fs::read(&file), file)) {
+        Some((Ok(body), file)) => {
+            debug!("200 {}", target);
+            respond(&mut stream, "200 OK", content_type(&file), &body, head_only);
+        }
+        Some((Err(_), _)) => {
+            warn!("404 {}", target);
+            respond(
+                &mut stream,
+                "404 Not Found",
+                "text/plain",
+                b"Not found",
+                head_only,
+            );
+        }
+        None => {
+            warn!("403 {}", target);
+            respond(
+                &mut stream,
+                "403 Forbidden",
+                "text/plain",
+                b"Forbidden",
+                head_only,
+            );
+        }
+    }
+}
+
+/// Serves `root` on `127.0.0.1:port` until the process is stopped.
+pub fn serve(root: &Path, port: u16) -> Result<(), String> {
+    if !root.is_dir() {
+        return Err(format!("{} is not a folder", root.display()));
+    }
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| format!("Can't listen on port {}: {}", port, e))?;
+    info!("Serving {} on http://localhost:{}/", root.display(), port);
+    info!("Press Ctrl+C to stop");
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let root = root.to_path_buf();
+                thread::spawn(move || handle(&root, stream));
+            }
+            Err(e) => warn!("Connection failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve;
+    use std::path::Path;
+
+    #[test]
+    fn refuses_paths_out_of_the_root() {
+        let root = Path::new("/srv/book");
+        assert_eq!(resolve(root, "/ch1.html?x#y"), Some(root.join("ch1.html")));
+        assert_eq!(resolve(root, "/images/./a%20b.png"), Some(root.join("images/a b.png")));
+        assert_eq!(resolve(root, "/../etc/passwd"), None);
+        assert_eq!(resolve(root, "/images/%2e%2e/%2e%2e/secret"), None);
+    }
+}
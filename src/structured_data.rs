@@ -0,0 +1,242 @@
+// schema.org JSON-LD for the generated pages.
+//
+// Built from the same `BookMetadata` the templates get, so the book object
+// lands on the cover, toc and chapter pages and every chapter points back
+// at it through `isPartOf`.
+
+use serde_json::{Map, Value};
+
+use super::metadata::{BookMetadata, Contributor};
+
+fn insert_if_present(object: &mut Map<String, Value>, key: &str, value: &str) {
+    if !value.is_empty() {
+        object.insert(key.to_string(), Value::from(value));
+    }
+}
+
+/// Pulls an ISBN out of a `dc:identifier` value such as `urn:isbn:978-1-934145-00-5`.
+/// Returns `None` for UUIDs and anything else that isn't a 10 or 13 digit ISBN
+/// with a valid check digit.
+pub fn isbn_from_identifier(identifier: &str) -> Option<String> {
+    let lower = identifier.trim().to_lowercase();
+    let value = lower
+        .trim_start_matches("urn:")
+        .trim_start_matches("isbn:")
+        .trim();
+    let digits: String = value
+        .chars()
+        .filter(|c| *c != '-' && *c != ' ')
+        .collect::<String>()
+        .to_uppercase();
+
+    // a check digit of X stands for 10
+    let values: Vec<u32> = digits
+        .chars()
+        .map(|c| c.to_digit(10).unwrap_or(10))
+        .collect();
+    let weighted_sum = |weights: &[u32]| -> u32 {
+        values
+            .iter()
+            .zip(weights.iter().cycle())
+            .map(|(d, w)| d * w)
+            .sum()
+    };
+    let valid = match digits.len() {
+        10 => {
+            digits[..9].chars().all(|c| c.is_ascii_digit())
+                && digits[9..].chars().all(|c| c.is_ascii_digit() || c == 'X')
+                && weighted_sum(&[10, 9, 8, 7, 6, 5, 4, 3, 2, 1]) % 11 == 0
+        }
+        13 => digits.chars().all(|c| c.is_ascii_digit()) && weighted_sum(&[1, 3]) % 10 == 0,
+        _ => false,
+    };
+
+    if valid {
+        Some(digits)
+    } else {
+        None
+    }
+}
+
+fn person(contributor: &Contributor) -> Value {
+    json!({ "@type": "Person", "name": contributor.name })
+}
+
+/// The schema.org `Book` object, without the `@context` so it can be nested.
+fn book_object(metadata: &BookMetadata) -> Map<String, Value> {
+    let mut object = Map::new();
+    object.insert("@type".to_string(), Value::from("Book"));
+    insert_if_present(&mut object, "@id", &metadata.info_url);
+    insert_if_present(&mut object, "name", &metadata.title);
+    insert_if_present(&mut object, "url", &metadata.info_url);
+    insert_if_present(&mut object, "datePublished", &metadata.date);
+    insert_if_present(&mut object, "dateModified", &metadata.modified);
+    insert_if_present(&mut object, "inLanguage", &metadata.language);
+    insert_if_present(&mut object, "isbn", &metadata.isbn);
+    insert_if_present(&mut object, "copyrightNotice", &metadata.rights);
+    insert_if_present(&mut object, "description", &metadata.description);
+
+    if !metadata.creators.is_empty() {
+        let authors: Vec<Value> = metadata.creators.iter().map(person).collect();
+        object.insert("author".to_string(), Value::from(authors));
+    }
+
+    // schema.org only has properties for some of the MARC relator roles
+    for (role, property) in [
+        ("trl", "translator"),
+        ("ill", "illustrator"),
+        ("edt", "editor"),
+    ]
+    .iter()
+    {
+        let people: Vec<Value> = metadata
+            .contributors
+            .iter()
+            .filter(|c| c.role == *role)
+            .map(person)
+            .collect();
+        if !people.is_empty() {
+            object.insert(property.to_string(), Value::from(people));
+        }
+    }
+
+    if !metadata.publisher.is_empty() {
+        object.insert(
+            "publisher".to_string(),
+            json!({ "@type": "Organization", "name": metadata.publisher }),
+        );
+    }
+
+    if !metadata.subjects.is_empty() {
+        object.insert(
+            "keywords".to_string(),
+            Value::from(metadata.subjects.join(", ")),
+        );
+    }
+
+    if let Some(series) = &metadata.series {
+        object.insert(
+            "isPartOf".to_string(),
+            json!({ "@type": "BookSeries", "name": series.name }),
+        );
+        insert_if_present(&mut object, "position", &series.position);
+    }
+
+    let accessibility = &metadata.accessibility;
+    if !accessibility.access_modes.is_empty() {
+        object.insert(
+            "accessMode".to_string(),
+            Value::from(accessibility.access_modes.clone()),
+        );
+    }
+    if !accessibility.access_modes_sufficient.is_empty() {
+        object.insert(
+            "accessModeSufficient".to_string(),
+            Value::from(accessibility.access_modes_sufficient.clone()),
+        );
+    }
+    if !accessibility.features.is_empty() {
+        object.insert(
+            "accessibilityFeature".to_string(),
+            Value::from(accessibility.features.clone()),
+        );
+    }
+    if !accessibility.hazards.is_empty() {
+        object.insert(
+            "accessibilityHazard".to_string(),
+            Value::from(accessibility.hazards.clone()),
+        );
+    }
+    insert_if_present(&mut object, "accessibilitySummary", &accessibility.summary);
+
+    if !metadata.base_url.is_empty() {
+        object.insert(
+            "image".to_string(),
+            Value::from(format!("{}/cover.jpg", metadata.base_url)),
+        );
+    }
+
+    object
+}
+
+// JSON-LD ends up inside a <script> tag, so a `</script>` in a title or
+// description must not be able to close it early.
+fn to_script_json(value: &Value) -> String {
+    serde_json::to_string(value)
+        .expect("Can't serialize JSON-LD")
+        .replace("</", "<\\/")
+}
+
+/// JSON-LD for the book itself, used on the cover and TOC pages.
+pub fn book_json_ld(metadata: &BookMetadata) -> String {
+    let mut object = book_object(metadata);
+    object.insert("@context".to_string(), Value::from("https://schema.org"));
+    to_script_json(&Value::Object(object))
+}
+
+/// JSON-LD for a single chapter page. `position` is 1-based spine order.
+pub fn chapter_json_ld(
+    metadata: &BookMetadata,
+    name: &str,
+    filename: &str,
+    position: usize,
+) -> String {
+    let mut object = Map::new();
+    object.insert("@context".to_string(), Value::from("https://schema.org"));
+    object.insert("@type".to_string(), Value::from("Chapter"));
+    if !name.is_empty() {
+        object.insert("name".to_string(), Value::from(name));
+    }
+    if !metadata.base_url.is_empty() {
+        object.insert(
+            "url".to_string(),
+            Value::from(format!("{}/{}", metadata.base_url, filename)),
+        );
+    }
+    object.insert("position".to_string(), Value::from(position));
+    object.insert("isPartOf".to_string(), Value::Object(book_object(metadata)));
+    to_script_json(&Value::Object(object))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::isbn_from_identifier;
+
+    #[test]
+    fn reads_isbn_urns_and_bare_isbns() {
+        assert_eq!(
+            isbn_from_identifier("urn:isbn:978-1-934145-00-5"),
+            Some("9781934145005".to_string())
+        );
+        assert_eq!(
+            isbn_from_identifier("ISBN: 0 306 40615 2"),
+            Some("0306406152".to_string())
+        );
+        assert_eq!(
+            isbn_from_identifier(" 9781934145005 "),
+            Some("9781934145005".to_string())
+        );
+    }
+
+    #[test]
+    fn keeps_the_x_check_digit_of_isbn_10() {
+        assert_eq!(
+            isbn_from_identifier("urn:isbn:080442957x"),
+            Some("080442957X".to_string())
+        );
+        assert_eq!(isbn_from_identifier("08044X9570"), None);
+        assert_eq!(isbn_from_identifier("0804429571"), None);
+        assert_eq!(isbn_from_identifier("9781934145006"), None);
+    }
+
+    #[test]
+    fn rejects_other_identifiers() {
+        assert_eq!(
+            isbn_from_identifier("urn:uuid:3f2a3c8e-1d2b-4c5d-9e6f-7a8b9c0d1e2f"),
+            None
+        );
+        assert_eq!(isbn_from_identifier("https://example.com/book"), None);
+        assert_eq!(isbn_from_identifier("978-1-934145"), None);
+        assert_eq!(isbn_from_identifier(""), None);
+    }
+}
@@ -0,0 +1,168 @@
+// Layered template and static asset lookup.
+//
+// The built-in `templates/` and `static/` folders are compiled into the
+// binary and form the bottom layer, so the converter runs from any folder.
+// A batch can name its own folders with `templates` and `static`, and each
+// book can add another layer on top of that. A layer only needs the files
+// it changes, e.g. a theme that only ships `page.html`.
+
+use fs_extra::dir::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tera::Tera;
+
+use super::Book;
+
+static BUILTIN_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "accessibility-report.html",
+        include_str!("../templates/accessibility-report.html"),
+    ),
+    ("fixed.html", include_str!("../templates/fixed.html")),
+    ("index.html", include_str!("../templates/index.html")),
+    ("library.html", include_str!("../templates/library.html")),
+    ("library.webmanifest", include_str!("../templates/library.webmanifest")),
+    ("manifest.webmanifest", include_str!("../templates/manifest.webmanifest")),
+    ("page.html", include_str!("../templates/page.html")),
+    ("toc.html", include_str!("../templates/toc.html")),
+];
+
+static BUILTIN_STATIC: &[(&str, &[u8])] = &[
+    ("app.js", include_bytes!("../static/app.js")),
+    ("fixed.css", include_bytes!("../static/fixed.css")),
+    ("fixed.js", include_bytes!("../static/fixed.js")),
+    ("library.css", include_bytes!("../static/library.css")),
+    ("library.js", include_bytes!("../static/library.js")),
+    ("logo.svg", include_bytes!("../static/logo.svg")),
+    ("mobile.css", include_bytes!("../static/mobile.css")),
+    ("normalize.css", include_bytes!("../static/normalize.css")),
+    ("overlay.js", include_bytes!("../static/overlay.js")),
+    ("reader.css", include_bytes!("../static/reader.css")),
+    ("sw.js", include_bytes!("../static/sw.js")),
+];
+
+fn write_file(path: &Path, data: &[u8]) {
+    if let Some(parent) = path.parent() {
+        let _resp = fs::create_dir_all(parent);
+    }
+    fs::write(path, data).unwrap_or_else(|e| panic!("Can't write {}: {}", path.display(), e));
+}
+
+/// Template and static folders layered over the built-in ones, lowest
+/// priority first.
+#[derive(Clone, Default)]
+pub struct ResourceDirs {
+    pub templates: Vec<String>,
+    pub statics: Vec<String>,
+}
+
+fn push_layer(layers: &mut Vec<String>, dir: &str) {
+    if !dir.is_empty() && !layers.iter().any(|l| l == dir) {
+        layers.push(dir.to_string());
+    }
+}
+
+impl ResourceDirs {
+    pub fn new(templates: &str, static_dir: &str) -> ResourceDirs {
+        let mut dirs = ResourceDirs::default();
+        push_layer(&mut dirs.templates, templates);
+        push_layer(&mut dirs.statics, static_dir);
+        dirs
+    }
+
+    /// These layers with the book's own folders on top.
+    pub fn for_book(&self, book: &Book) -> ResourceDirs {
+        let mut dirs = self.clone();
+        push_layer(&mut dirs.templates, &book.templates);
+        push_layer(&mut dirs.statics, &book.static_dir);
+        dirs
+    }
+
+    /// Compiles the built-in templates with every layer replacing templates
+    /// by name. Inheritance is resolved after all layers are in, so a custom
+    /// `page.html` is also what the built-in `index.html` extends.
+    pub fn tera(&self) -> Tera {
+        let mut sources: BTreeMap<String, String> = BUILTIN_TEMPLATES
+            .iter()
+            .map(|(name, content)| (name.to_string(), content.to_string()))
+            .collect();
+
+        for dir in self.templates.iter() {
+            let content = get_dir_content(dir)
+                .unwrap_or_else(|e| panic!("Can't read templates folder {}: {}", dir, e));
+            for file in content.files {
+                let name = Path::new(&file)
+                    .strip_prefix(dir)
+                    .expect("template outside of its folder")
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                let content = fs::read_to_string(&file)
+                    .unwrap_or_else(|e| panic!("Can't read template {}: {}", file, e));
+                sources.insert(name, content);
+            }
+        }
+
+        let mut tera = Tera::default();
+        tera.add_raw_templates(
+            sources
+                .iter()
+                .map(|(name, content)| (name.as_str(), content.as_str()))
+                .collect(),
+        )
+        .expect("Can't compile templates");
+        tera.autoescape_on(vec!["html"]);
+        tera
+    }
+
+    /// Copies the built-in static folder into `resources/static`, then each
+    /// layer over it.
+    pub fn copy_static(&self, output_root: &Path) {
+        debug!("Copying static resources...");
+        let mut options = CopyOptions::new();
+        options.copy_inside = true;
+        options.overwrite = true;
+
+        let destination = output_root.join("resources").join("static");
+        for (name, data) in BUILTIN_STATIC.iter() {
+            trace!("copy {}", name);
+            write_file(&destination.join(name), data);
+        }
+
+        for dir in self.statics.iter() {
+            let handle = |process_info: fs_extra::TransitProcess| {
+                trace!("copy {}", process_info.file_name);
+                fs_extra::dir::TransitProcessResult::ContinueOrAbort
+            };
+            let mut items = vec![];
+            for entry in fs::read_dir(dir)
+                .unwrap_or_else(|e| panic!("Can't read static folder {}: {}", dir, e))
+            {
+                items.push(entry.expect("Can't read static folder entry").path());
+            }
+            fs_extra::copy_items_with_progress(&items, &destination, &options, handle)
+                .expect("failed copying static resource");
+        }
+    }
+}
+
+/// Writes the built-in templates and static files to `folder`, as a
+/// starting point for a custom theme. Existing files are left alone unless
+/// `overwrite` is set.
+pub fn eject(folder: &Path, overwrite: bool) {
+    let templates = BUILTIN_TEMPLATES
+        .iter()
+        .map(|(name, content)| (folder.join("templates").join(name), content.as_bytes()));
+    let statics = BUILTIN_STATIC
+        .iter()
+        .map(|(name, data)| (folder.join("static").join(name), *data));
+
+    for (path, data) in templates.chain(statics) {
+        if path.exists() && !overwrite {
+            info!("  skip: {} (already exists)", path.display());
+            continue;
+        }
+        write_file(&path, data);
+        info!("  wrote: {}", path.display());
+    }
+}
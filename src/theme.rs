@@ -0,0 +1,275 @@
+// Per-book colors for the manifest, the reader chrome and the icon.
+//
+// Colors set on the book in the batch JSON win. Anything left out is
+// derived from a palette of the decoded cover, and books without a usable
+// cover fall back to the original Himalayan Academy colors.
+
+use epub::doc::EpubDoc;
+use image::DynamicImage;
+use std::collections::HashMap;
+
+use super::Book;
+
+const DEFAULT_THEME_COLOR: &str = "#8c3945";
+const DEFAULT_BACKGROUND_COLOR: &str = "#ffffff";
+
+#[derive(Serialize, Clone)]
+pub struct Theme {
+    pub theme_color: String,
+    pub background_color: String,
+    pub accent_color: String,
+    /// Readable text color on top of `theme_color`, for the header.
+    pub theme_text_color: String,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            theme_color: DEFAULT_THEME_COLOR.to_string(),
+            background_color: DEFAULT_BACKGROUND_COLOR.to_string(),
+            accent_color: DEFAULT_THEME_COLOR.to_string(),
+            theme_text_color: "#ffffff".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Swatch {
+    rgb: [f32; 3],
+    count: u32,
+}
+
+impl Swatch {
+    fn hsl(&self) -> (f32, f32, f32) {
+        let [r, g, b] = self.rgb;
+        let (r, g, b) = (r / 255.0, g / 255.0, b / 255.0);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let l = (max + min) / 2.0;
+        if max == min {
+            return (0.0, 0.0, l);
+        }
+        let d = max - min;
+        let s = if l > 0.5 {
+            d / (2.0 - max - min)
+        } else {
+            d / (max + min)
+        };
+        let h = if max == r {
+            (g - b) / d + if g < b { 6.0 } else { 0.0 }
+        } else if max == g {
+            (b - r) / d + 2.0
+        } else {
+            (r - g) / d + 4.0
+        };
+        (h * 60.0, s, l)
+    }
+}
+
+fn to_hex(rgb: [f32; 3]) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        rgb[0].round().clamp(0.0, 255.0) as u8,
+        rgb[1].round().clamp(0.0, 255.0) as u8,
+        rgb[2].round().clamp(0.0, 255.0) as u8
+    )
+}
+
+fn mix(rgb: [f32; 3], with: [f32; 3], amount: f32) -> [f32; 3] {
+    [
+        rgb[0] + (with[0] - rgb[0]) * amount,
+        rgb[1] + (with[1] - rgb[1]) * amount,
+        rgb[2] + (with[2] - rgb[2]) * amount,
+    ]
+}
+
+// WCAG relative luminance
+fn luminance(rgb: [u8; 3]) -> f32 {
+    let channel = |c: u8| {
+        let c = f32::from(c) / 255.0;
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * channel(rgb[0]) + 0.7152 * channel(rgb[1]) + 0.0722 * channel(rgb[2])
+}
+
+/// Parses `#rgb` and `#rrggbb` colors.
+pub fn parse_hex(color: &str) -> Option<[u8; 3]> {
+    let hex = color.trim().trim_start_matches('#');
+    // byte slicing below needs ASCII, and from_str_radix would take a `+`
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let expanded: String = match hex.len() {
+        3 => hex.chars().flat_map(|c| vec![c, c]).collect(),
+        6 => hex.to_string(),
+        _ => return None,
+    };
+    let channel = |i: usize| u8::from_str_radix(&expanded[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn text_color_on(background: &str) -> String {
+    match parse_hex(background) {
+        Some(rgb) if luminance(rgb) > 0.4 => "#000000".to_string(),
+        _ => "#ffffff".to_string(),
+    }
+}
+
+// Buckets the pixels of a thumbnail into 4 bits per channel and averages
+// each bucket, most frequent first.
+fn swatches(img: &DynamicImage) -> Vec<Swatch> {
+    let thumbnail = img.thumbnail(64, 64).to_rgb8();
+    let mut buckets: HashMap<(u8, u8, u8), ([u64; 3], u32)> = HashMap::new();
+    for pixel in thumbnail.pixels() {
+        let [r, g, b] = pixel.0;
+        let entry = buckets
+            .entry((r >> 4, g >> 4, b >> 4))
+            .or_insert(([0; 3], 0));
+        entry.0[0] += u64::from(r);
+        entry.0[1] += u64::from(g);
+        entry.0[2] += u64::from(b);
+        entry.1 += 1;
+    }
+
+    let mut swatches: Vec<Swatch> = buckets
+        .values()
+        .map(|(sum, count)| Swatch {
+            rgb: [
+                sum[0] as f32 / *count as f32,
+                sum[1] as f32 / *count as f32,
+                sum[2] as f32 / *count as f32,
+            ],
+            count: *count,
+        })
+        .collect();
+    swatches.sort_by_key(|s| ::std::cmp::Reverse(s.count));
+    swatches
+}
+
+fn hue_distance(a: f32, b: f32) -> f32 {
+    let d = (a - b).abs();
+    d.min(360.0 - d)
+}
+
+/// Picks theme, background and accent colors out of a cover image.
+pub fn palette_from_image(img: &DynamicImage) -> Theme {
+    let swatches = swatches(img);
+    let total: u32 = swatches.iter().map(|s| s.count).sum();
+    if total == 0 {
+        return Theme::default();
+    }
+
+    // the theme color shows white text in the header, so it should be
+    // neither washed out nor black, and favour saturated colors
+    let score = |s: &Swatch| {
+        let (_, saturation, lightness) = s.hsl();
+        if !(0.15..=0.75).contains(&lightness) {
+            0.0
+        } else {
+            s.count as f32 * (0.2 + saturation)
+        }
+    };
+    let theme = swatches
+        .iter()
+        .filter(|s| score(s) > 0.0)
+        .max_by(|a, b| score(a).partial_cmp(&score(b)).unwrap())
+        .cloned();
+
+    let theme = match theme {
+        Some(t) => t,
+        None => return Theme::default(),
+    };
+    let (theme_hue, _, _) = theme.hsl();
+
+    // a pale tint of the most common color keeps body text readable
+    let dominant = swatches[0];
+    let background = mix(dominant.rgb, [255.0; 3], 0.9);
+
+    // the accent is the most visible color that's clearly a different hue
+    let accent = swatches
+        .iter()
+        .filter(|s| s.count * 50 >= total)
+        .filter(|s| {
+            let (hue, saturation, lightness) = s.hsl();
+            saturation > 0.25
+                && (0.2..=0.8).contains(&lightness)
+                && hue_distance(hue, theme_hue) > 40.0
+        })
+        .map(|s| s.rgb)
+        .next()
+        .unwrap_or_else(|| mix(theme.rgb, [0.0; 3], 0.3));
+
+    let theme_color = to_hex(theme.rgb);
+    Theme {
+        theme_text_color: text_color_on(&theme_color),
+        theme_color,
+        background_color: to_hex(background),
+        accent_color: to_hex(accent),
+    }
+}
+
+fn cover_image(book: &Book) -> Option<DynamicImage> {
+    let mut doc = EpubDoc::new(&book.epub).ok()?;
+    let data = doc.get_cover().ok()?;
+    image::load_from_memory(&data).ok()
+}
+
+/// The theme for `book`, colors from the batch JSON first, then the cover.
+pub fn book_theme(book: &Book) -> Theme {
+    let explicit = |color: &str| {
+        if color.is_empty() {
+            None
+        } else if parse_hex(color).is_some() {
+            Some(color.to_string())
+        } else {
+            warn!("Ignoring invalid color {} for {}", color, &book.epub);
+            None
+        }
+    };
+    let theme_color = explicit(&book.theme_color);
+    let background_color = explicit(&book.background_color);
+    let accent_color = explicit(&book.accent_color);
+
+    let derived = if theme_color.is_some() && background_color.is_some() && accent_color.is_some() {
+        Theme::default()
+    } else {
+        match cover_image(book) {
+            Some(img) => palette_from_image(&img),
+            None => Theme::default(),
+        }
+    };
+
+    let theme_color = theme_color.unwrap_or(derived.theme_color);
+    Theme {
+        theme_text_color: text_color_on(&theme_color),
+        accent_color: accent_color.unwrap_or(derived.accent_color),
+        background_color: background_color.unwrap_or(derived.background_color),
+        theme_color,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_hex;
+
+    #[test]
+    fn parses_short_and_long_hex_colors() {
+        assert_eq!(parse_hex("#fff"), Some([255, 255, 255]));
+        assert_eq!(parse_hex(" #1A2b3c "), Some([0x1a, 0x2b, 0x3c]));
+        assert_eq!(parse_hex("08f"), Some([0x00, 0x88, 0xff]));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        assert_eq!(parse_hex("#a\u{e9}bcd"), None);
+        assert_eq!(parse_hex("#+1+2+3"), None);
+        assert_eq!(parse_hex("#ggg"), None);
+        assert_eq!(parse_hex("#ffff"), None);
+        assert_eq!(parse_hex("red"), None);
+        assert_eq!(parse_hex(""), None);
+    }
+}
@@ -0,0 +1,383 @@
+// `validate`: checks a generated book folder before it gets deployed.
+//
+// Every HTML page is parsed and each relative `href`/`src` must point at a
+// file in the folder, with the `#fragment` present in the target page. The
+// previous/next buttons have to walk the spine in `spine.csv` from start to
+// end, and the manifest icons and `sw.js` must be there for the PWA to
+// install. Links starting with `/` depend on where the book is hosted and
+// are not checked.
+
+use fs_extra::dir::get_dir_content;
+use scraper::{Html, Selector};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::css::percent_decode;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub kind: String,
+    pub file: String,
+    pub target: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct ValidationReport {
+    pub folder: String,
+    pub pages: usize,
+    pub links_checked: usize,
+    pub errors: usize,
+    pub warnings: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl ValidationReport {
+    fn add(&mut self, severity: Severity, kind: &str, file: &str, target: &str, message: String) {
+        match severity {
+            Severity::Error => self.errors += 1,
+            Severity::Warning => self.warnings += 1,
+        }
+        self.findings.push(Finding {
+            severity,
+            kind: kind.to_string(),
+            file: file.to_string(),
+            target: target.to_string(),
+            message,
+        });
+    }
+}
+
+struct Page {
+    ids: HashSet<String>,
+    links: Vec<String>,
+    previous: Option<String>,
+    next: Option<String>,
+}
+
+fn parse_page(html: &str) -> Page {
+    let document = Html::parse_document(html);
+    let all = Selector::parse("*").unwrap();
+    let previous = Selector::parse("a.go-previous").unwrap();
+    let next = Selector::parse("a.go-next").unwrap();
+
+    let mut page = Page {
+        ids: HashSet::new(),
+        links: vec![],
+        previous: None,
+        next: None,
+    };
+    for element in document.select(&all) {
+        let value = element.value();
+        if let Some(id) = value.attr("id") {
+            page.ids.insert(id.to_string());
+        }
+        // <a name> anchors still show up in older ePubs
+        if value.name() == "a" {
+            if let Some(name) = value.attr("name") {
+                page.ids.insert(name.to_string());
+            }
+        }
+        for attr in ["href", "src"].iter() {
+            if let Some(link) = value.attr(attr) {
+                page.links.push(link.trim().to_string());
+            }
+        }
+    }
+    let href = |selector: &Selector| {
+        document
+            .select(selector)
+            .next()
+            .and_then(|a| a.value().attr("href"))
+            .map(|h| h.to_string())
+    };
+    page.previous = href(&previous);
+    page.next = href(&next);
+    page
+}
+
+// Links that leave the folder or depend on where it's hosted.
+fn is_external(link: &str) -> bool {
+    if link.starts_with('/') {
+        return true;
+    }
+    match link.find(':') {
+        Some(colon) => !link[..colon].contains('/'),
+        None => false,
+    }
+}
+
+// Splits a link into the file it points at, relative to `root`, and its
+// fragment. An empty file means the page the link is on.
+fn resolve(root: &Path, page: &Path, link: &str) -> (PathBuf, String) {
+    let (path, fragment) = match link.find('#') {
+        Some(i) => (&link[..i], percent_decode(&link[i + 1..])),
+        None => (link, String::new()),
+    };
+    let path = path.split('?').next().unwrap_or_default();
+    if path.is_empty() {
+        return (page.to_path_buf(), fragment);
+    }
+
+    let mut file = page.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    for part in percent_decode(path).split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                file.pop();
+            }
+            part => file.push(part),
+        }
+    }
+    if root.join(&file).is_dir() {
+        file.push("index.html");
+    }
+    (file, fragment)
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+fn check_links(root: &Path, pages: &HashMap<PathBuf, Page>, report: &mut ValidationReport) {
+    let mut names: Vec<&PathBuf> = pages.keys().collect();
+    names.sort();
+    for name in names {
+        let page = &pages[name];
+        let file = display(name);
+        for link in page.links.iter() {
+            if link.is_empty() || is_external(link) {
+                continue;
+            }
+            report.links_checked += 1;
+            let (target, fragment) = resolve(root, name, link);
+            if !root.join(&target).is_file() {
+                report.add(
+                    Severity::Error,
+                    "broken-link",
+                    &file,
+                    link,
+                    format!("{} does not exist", display(&target)),
+                );
+                continue;
+            }
+            if fragment.is_empty() {
+                continue;
+            }
+            if let Some(target_page) = pages.get(&target) {
+                if !target_page.ids.contains(&fragment) {
+                    report.add(
+                        Severity::Error,
+                        "missing-anchor",
+                        &file,
+                        link,
+                        format!(
+                            "no element with id \"{}\" in {}",
+                            fragment,
+                            display(&target)
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn spine_files(root: &Path) -> Option<Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(root.join("spine.csv"))
+        .ok()?;
+    Some(
+        reader
+            .records()
+            .filter_map(|r| r.ok())
+            .filter_map(|r| r.get(1).map(|s| s.to_string()))
+            .collect(),
+    )
+}
+
+fn check_navigation(root: &Path, pages: &HashMap<PathBuf, Page>, report: &mut ValidationReport) {
+    let spine = match spine_files(root) {
+        Some(spine) => spine,
+        None => {
+            report.add(
+                Severity::Error,
+                "navigation",
+                "spine.csv",
+                "",
+                "spine.csv is missing, can't check previous/next".to_string(),
+            );
+            return;
+        }
+    };
+
+    for (i, filename) in spine.iter().enumerate() {
+        let page = match pages.get(&PathBuf::from(filename)) {
+            Some(page) => page,
+            None => {
+                report.add(
+                    Severity::Error,
+                    "navigation",
+                    filename,
+                    "",
+                    format!("spine item {} has no page", i + 1),
+                );
+                continue;
+            }
+        };
+        let expected_previous = if i > 0 { spine.get(i - 1) } else { None };
+        let expected_next = spine.get(i + 1);
+        for (kind, found, expected) in [
+            ("previous", &page.previous, expected_previous),
+            ("next", &page.next, expected_next),
+        ]
+        .iter()
+        {
+            if found.as_ref() != *expected {
+                report.add(
+                    Severity::Error,
+                    "navigation",
+                    filename,
+                    found.as_deref().unwrap_or_default(),
+                    format!(
+                        "{} link should be {}",
+                        kind,
+                        expected.map(|s| s.as_str()).unwrap_or("absent")
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn check_pwa(root: &Path, report: &mut ValidationReport) {
+    if !root.join("sw.js").is_file() {
+        report.add(
+            Severity::Error,
+            "service-worker",
+            "sw.js",
+            "",
+            "sw.js is missing".to_string(),
+        );
+    }
+
+    let manifest: serde_json::Value = match fs::read_to_string(root.join("manifest.webmanifest"))
+        .map_err(|e| e.to_string())
+        .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+    {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            report.add(
+                Severity::Error,
+                "manifest",
+                "manifest.webmanifest",
+                "",
+                format!("can't read manifest: {}", e),
+            );
+            return;
+        }
+    };
+
+    let icons = manifest["icons"].as_array().cloned().unwrap_or_default();
+    if icons.is_empty() {
+        report.add(
+            Severity::Error,
+            "manifest",
+            "manifest.webmanifest",
+            "",
+            "manifest has no icons".to_string(),
+        );
+    }
+    let sources = icons
+        .iter()
+        .filter_map(|icon| icon["src"].as_str())
+        .chain(manifest["start_url"].as_str());
+    for src in sources {
+        if is_external(src) {
+            continue;
+        }
+        let (target, _) = resolve(root, Path::new("manifest.webmanifest"), src);
+        if !root.join(&target).is_file() {
+            report.add(
+                Severity::Error,
+                "manifest",
+                "manifest.webmanifest",
+                src,
+                format!("{} does not exist", display(&target)),
+            );
+        }
+    }
+}
+
+/// Validates the book in `folder`. Only an unreadable folder is an `Err`,
+/// problems with the book end up in the report.
+pub fn validate(folder: &str) -> Result<ValidationReport, String> {
+    let root = Path::new(folder);
+    let content =
+        get_dir_content(root).map_err(|e| format!("Can't read folder {}: {}", folder, e))?;
+
+    let mut report = ValidationReport {
+        folder: folder.to_string(),
+        ..Default::default()
+    };
+    let mut pages = HashMap::new();
+    for file in content.files.iter().filter(|f| f.ends_with(".html")) {
+        let name = Path::new(file)
+            .strip_prefix(root)
+            .expect("page outside of its folder")
+            .to_path_buf();
+        match fs::read_to_string(file) {
+            Ok(html) => {
+                pages.insert(name, parse_page(&html));
+            }
+            Err(e) => report.add(
+                Severity::Error,
+                "parse",
+                &display(&name),
+                "",
+                format!("can't read page: {}", e),
+            ),
+        }
+    }
+    report.pages = pages.len();
+
+    check_links(root, &pages, &mut report);
+    check_navigation(root, &pages, &mut report);
+    check_pwa(root, &mut report);
+    Ok(report)
+}
+
+/// Logs the findings, errors and warnings at their own level.
+pub fn log_report(report: &ValidationReport) {
+    for finding in report.findings.iter() {
+        let target = if finding.target.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", finding.target)
+        };
+        match finding.severity {
+            Severity::Error => error!(
+                "{}: {}{}: {}",
+                finding.file, finding.kind, target, finding.message
+            ),
+            Severity::Warning => warn!(
+                "{}: {}{}: {}",
+                finding.file, finding.kind, target, finding.message
+            ),
+        }
+    }
+    info!(
+        "{}: {} pages, {} links checked, {} errors, {} warnings",
+        report.folder, report.pages, report.links_checked, report.errors, report.warnings
+    );
+}
@@ -0,0 +1,215 @@
+// A minimal WOFF2 encoder.
+//
+// WOFF2 is the OpenType tables concatenated and compressed with Brotli,
+// behind a small header and table directory. The format also defines
+// transforms for `glyf`, `loca` and `hmtx` that make them compress better;
+// every table is stored with the null transform here, which any WOFF2
+// decoder has to accept and which keeps this short.
+
+use std::io::Cursor;
+
+pub type Table = ([u8; 4], Vec<u8>);
+
+const SFNT_TTC: u32 = 0x7474_6366; // 'ttcf'
+const WOFF2_SIGNATURE: u32 = 0x774F_4632; // 'wOF2'
+const HEADER_SIZE: usize = 48;
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| "font is truncated".to_string())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "font is truncated".to_string())
+}
+
+/// The flavor (TrueType or CFF) and tables of an OpenType font. Font
+/// collections are not supported.
+pub fn read_sfnt(data: &[u8]) -> Result<(u32, Vec<Table>), String> {
+    let flavor = read_u32(data, 0)?;
+    if flavor == SFNT_TTC {
+        return Err("font collections can't be converted".to_string());
+    }
+    let count = read_u16(data, 4)? as usize;
+    let mut tables = Vec::with_capacity(count);
+    for i in 0..count {
+        let record = 12 + i * 16;
+        let mut tag = [0; 4];
+        tag.copy_from_slice(
+            data.get(record..record + 4)
+                .ok_or_else(|| "font is truncated".to_string())?,
+        );
+        let offset = read_u32(data, record + 8)? as usize;
+        let length = read_u32(data, record + 12)? as usize;
+        let table = data
+            .get(offset..offset + length)
+            .ok_or_else(|| format!("table {} is out of bounds", String::from_utf8_lossy(&tag)))?;
+        tables.push((tag, table.to_vec()));
+    }
+    Ok((flavor, tables))
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+// UIntBase128: big-endian groups of 7 bits, high bit set on all but the last.
+fn push_base128(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    out.extend_from_slice(&bytes);
+}
+
+fn padded(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// Encodes the tables of an OpenType font as WOFF2.
+pub fn encode(flavor: u32, mut tables: Vec<Table>) -> Result<Vec<u8>, String> {
+    // decoders want `loca` right after `glyf`, otherwise tables go by tag
+    tables.sort_by_key(|table| table.0);
+    let loca = tables.iter().position(|t| &t.0 == b"loca");
+    let glyf = tables.iter().position(|t| &t.0 == b"glyf");
+    if let (Some(loca), Some(glyf)) = (loca, glyf) {
+        let loca = tables.remove(loca);
+        tables.insert(glyf + 1, loca);
+    }
+
+    let mut directory = vec![];
+    let mut stream = vec![];
+    for (tag, data) in tables.iter() {
+        // 63 means the tag follows instead of being one of the known ones.
+        // The null transform is 3 for glyf and loca and 0 for the rest.
+        let transform = if tag == b"glyf" || tag == b"loca" {
+            3
+        } else {
+            0
+        };
+        directory.push(63 | transform << 6);
+        directory.extend_from_slice(tag);
+        push_base128(&mut directory, data.len() as u32);
+        stream.extend_from_slice(data);
+    }
+
+    let mut compressed = vec![];
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: 11,
+        mode: brotli::enc::backward_references::BrotliEncoderMode::BROTLI_MODE_FONT,
+        size_hint: stream.len(),
+        ..Default::default()
+    };
+    brotli::BrotliCompress(&mut Cursor::new(&stream), &mut compressed, &params)
+        .map_err(|e| format!("can't compress font: {}", e))?;
+
+    let sfnt_size = 12
+        + 16 * tables.len()
+        + tables
+            .iter()
+            .map(|(_, data)| padded(data.len()))
+            .sum::<usize>();
+    let length = padded(HEADER_SIZE + directory.len() + compressed.len());
+
+    let mut woff2 = Vec::with_capacity(length);
+    push_u32(&mut woff2, WOFF2_SIGNATURE);
+    push_u32(&mut woff2, flavor);
+    push_u32(&mut woff2, length as u32);
+    push_u16(&mut woff2, tables.len() as u16);
+    push_u16(&mut woff2, 0); // reserved
+    push_u32(&mut woff2, sfnt_size as u32);
+    push_u32(&mut woff2, compressed.len() as u32);
+    push_u16(&mut woff2, 1); // major version
+    push_u16(&mut woff2, 0); // minor version
+    for _ in 0..5 {
+        push_u32(&mut woff2, 0); // no metadata or private data
+    }
+    woff2.extend_from_slice(&directory);
+    woff2.extend_from_slice(&compressed);
+    woff2.resize(length, 0);
+    Ok(woff2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, push_base128, read_sfnt, read_u16, read_u32, HEADER_SIZE};
+    use std::io::Cursor;
+
+    const TRUETYPE: u32 = 0x0001_0000;
+
+    #[test]
+    fn writes_base128_numbers() {
+        let mut out = vec![];
+        push_base128(&mut out, 63);
+        push_base128(&mut out, 128);
+        push_base128(&mut out, 0x3fff);
+        assert_eq!(out, vec![63, 0x81, 0x00, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn writes_the_header_and_table_directory() {
+        let tables = vec![
+            (*b"name", vec![1, 2, 3]),
+            (*b"loca", vec![4; 4]),
+            (*b"glyf", vec![5; 10]),
+            (*b"cmap", vec![6; 2]),
+        ];
+        let woff2 = encode(TRUETYPE, tables).unwrap();
+
+        assert_eq!(&woff2[0..4], b"wOF2");
+        assert_eq!(read_u32(&woff2, 4).unwrap(), TRUETYPE);
+        assert_eq!(read_u32(&woff2, 8).unwrap() as usize, woff2.len());
+        assert_eq!(woff2.len() % 4, 0);
+        assert_eq!(read_u16(&woff2, 12).unwrap(), 4);
+        // header and table records, then each table padded to 4 bytes
+        assert_eq!(read_u32(&woff2, 16).unwrap(), 12 + 4 * 16 + 4 + 12 + 4 + 4);
+        assert_eq!(read_u16(&woff2, 24).unwrap(), 1);
+
+        // tables by tag with loca after glyf, glyf and loca flagged as
+        // untransformed
+        let directory = [
+            &b"\x3fcmap\x02"[..],
+            b"\xffglyf\x0a",
+            b"\xffloca\x04",
+            b"\x3fname\x03",
+        ]
+        .concat();
+        let end = HEADER_SIZE + directory.len();
+        assert_eq!(&woff2[HEADER_SIZE..end], &directory[..]);
+
+        let compressed_size = read_u32(&woff2, 20).unwrap() as usize;
+        let mut stream = vec![];
+        brotli::BrotliDecompress(
+            &mut Cursor::new(&woff2[end..end + compressed_size]),
+            &mut stream,
+        )
+        .unwrap();
+        let expected: Vec<u8> = [vec![6; 2], vec![5; 10], vec![4; 4], vec![1, 2, 3]].concat();
+        assert_eq!(stream, expected);
+    }
+
+    #[test]
+    fn reads_sfnt_tables() {
+        let mut font = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        font.extend_from_slice(b"head");
+        font.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 28, 0, 0, 0, 2]);
+        font.extend_from_slice(&[7, 8]);
+        let (flavor, tables) = read_sfnt(&font).unwrap();
+        assert_eq!(flavor, TRUETYPE);
+        assert_eq!(tables, vec![(*b"head", vec![7, 8])]);
+
+        font.truncate(29);
+        assert!(read_sfnt(&font).is_err());
+        assert!(read_sfnt(b"ttcf").is_err());
+    }
+}
@@ -0,0 +1,379 @@
+// XHTML chapters as HTML.
+//
+// Chapters are XHTML, but scraper and the browser read the pages with the
+// HTML parser, which knows nothing of XML namespaces or self-closing tags:
+// `<m:math>` becomes an unknown element, `<a id="x"/>` swallows everything
+// after it and both branches of an `epub:switch` are shown. Chapters are
+// read here as XML and written back as markup the HTML parser builds the
+// same tree from, with MathML and SVG unprefixed so they land in foreign
+// content. Chapters that aren't well-formed XML are left to the HTML parser.
+
+use std::collections::BTreeSet;
+use xml::name::OwnedName;
+use xml::reader::{ParserConfig, XmlEvent};
+
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+const MATHML_NS: &str = "http://www.w3.org/1998/Math/MathML";
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const EPUB_NS: &str = "http://www.idpf.org/2007/ops";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// What an `epub:case` may require and still be picked.
+const SUPPORTED_NAMESPACES: &[&str] = &[XHTML_NS, MATHML_NS, SVG_NS];
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// MathML elements outside MathML Core, which browsers don't lay out.
+const UNRENDERED_MATHML: &[&str] = &[
+    "mfenced", "menclose", "mlabeledtr", "mglyph", "malignmark", "maligngroup", "mstack",
+    "mlongdiv", "msgroup", "msrow", "mscarries", "mscarry", "msline", "apply", "ci", "cn",
+    "csymbol", "bind", "bvar", "share", "cerror", "cbytes", "cs",
+];
+
+/// Named entities of the XHTML DTDs, which aren't loaded: Latin-1 from
+/// U+00A0 on, then the common typographic ones.
+const LATIN1_ENTITIES: &[&str] = &[
+    "nbsp", "iexcl", "cent", "pound", "curren", "yen", "brvbar", "sect", "uml", "copy", "ordf",
+    "laquo", "not", "shy", "reg", "macr", "deg", "plusmn", "sup2", "sup3", "acute", "micro",
+    "para", "middot", "cedil", "sup1", "ordm", "raquo", "frac14", "frac12", "frac34", "iquest",
+    "Agrave", "Aacute", "Acirc", "Atilde", "Auml", "Aring", "AElig", "Ccedil", "Egrave", "Eacute",
+    "Ecirc", "Euml", "Igrave", "Iacute", "Icirc", "Iuml", "ETH", "Ntilde", "Ograve", "Oacute",
+    "Ocirc", "Otilde", "Ouml", "times", "Oslash", "Ugrave", "Uacute", "Ucirc", "Uuml", "Yacute",
+    "THORN", "szlig", "agrave", "aacute", "acirc", "atilde", "auml", "aring", "aelig", "ccedil",
+    "egrave", "eacute", "ecirc", "euml", "igrave", "iacute", "icirc", "iuml", "eth", "ntilde",
+    "ograve", "oacute", "ocirc", "otilde", "ouml", "divide", "oslash", "ugrave", "uacute",
+    "ucirc", "uuml", "yacute", "thorn", "yuml",
+];
+const OTHER_ENTITIES: &[(&str, char)] = &[
+    ("OElig", '\u{152}'),
+    ("oelig", '\u{153}'),
+    ("Scaron", '\u{160}'),
+    ("scaron", '\u{161}'),
+    ("Yuml", '\u{178}'),
+    ("fnof", '\u{192}'),
+    ("circ", '\u{2c6}'),
+    ("tilde", '\u{2dc}'),
+    ("ensp", '\u{2002}'),
+    ("emsp", '\u{2003}'),
+    ("thinsp", '\u{2009}'),
+    ("zwnj", '\u{200c}'),
+    ("zwj", '\u{200d}'),
+    ("lrm", '\u{200e}'),
+    ("rlm", '\u{200f}'),
+    ("ndash", '\u{2013}'),
+    ("mdash", '\u{2014}'),
+    ("lsquo", '\u{2018}'),
+    ("rsquo", '\u{2019}'),
+    ("sbquo", '\u{201a}'),
+    ("ldquo", '\u{201c}'),
+    ("rdquo", '\u{201d}'),
+    ("bdquo", '\u{201e}'),
+    ("dagger", '\u{2020}'),
+    ("Dagger", '\u{2021}'),
+    ("bull", '\u{2022}'),
+    ("hellip", '\u{2026}'),
+    ("permil", '\u{2030}'),
+    ("prime", '\u{2032}'),
+    ("Prime", '\u{2033}'),
+    ("lsaquo", '\u{2039}'),
+    ("rsaquo", '\u{203a}'),
+    ("oline", '\u{203e}'),
+    ("frasl", '\u{2044}'),
+    ("euro", '\u{20ac}'),
+    ("trade", '\u{2122}'),
+    ("larr", '\u{2190}'),
+    ("uarr", '\u{2191}'),
+    ("rarr", '\u{2192}'),
+    ("darr", '\u{2193}'),
+    ("harr", '\u{2194}'),
+    ("minus", '\u{2212}'),
+    ("infin", '\u{221e}'),
+    ("ne", '\u{2260}'),
+    ("le", '\u{2264}'),
+    ("ge", '\u{2265}'),
+    ("loz", '\u{25ca}'),
+    ("spades", '\u{2660}'),
+    ("clubs", '\u{2663}'),
+    ("hearts", '\u{2665}'),
+    ("diams", '\u{2666}'),
+];
+
+// What became of an open element, so its end tag can do the same.
+enum Open {
+    /// Written, closed with this end tag (none for void elements).
+    Written(Option<String>),
+    /// Left out, its children are written in its place.
+    Unwrapped,
+    /// Left out with everything inside it.
+    Dropped,
+    /// An `epub:switch`, remembering whether a case was picked yet.
+    Switch(bool),
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_attribute(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;")
+}
+
+fn namespace(name: &OwnedName) -> &str {
+    name.namespace.as_deref().unwrap_or(XHTML_NS)
+}
+
+fn qualified(name: &OwnedName) -> String {
+    match &name.prefix {
+        Some(prefix) => format!("{}:{}", prefix, name.local_name),
+        None => name.local_name.clone(),
+    }
+}
+
+/// A parser that knows the named entities of the XHTML DTDs.
+pub fn parser_config() -> ParserConfig {
+    let mut config = ParserConfig::new();
+    for (i, name) in LATIN1_ENTITIES.iter().enumerate() {
+        let c = std::char::from_u32(0xa0 + i as u32).unwrap_or_default();
+        config = config.add_entity(*name, c.to_string());
+    }
+    for (name, c) in OTHER_ENTITIES.iter() {
+        config = config.add_entity(*name, c.to_string());
+    }
+    config
+}
+
+/// `xhtml` as markup the HTML parser reads the same way, or `None` when it
+/// isn't well-formed XML. Anything a browser won't render is warned about,
+/// with `path` to say where.
+pub fn to_html(xhtml: &str, path: &str) -> Option<String> {
+    let (html, warnings) = convert(xhtml, path)?;
+    for warning in warnings {
+        warn!("{}: {}", path, warning);
+    }
+    Some(html)
+}
+
+/// `to_html` without the warnings, which come back with the markup
+/// instead.
+pub fn convert(xhtml: &str, path: &str) -> Option<(String, BTreeSet<String>)> {
+    let config = parser_config()
+        .whitespace_to_characters(true)
+        .cdata_to_characters(true)
+        .ignore_comments(true)
+        .coalesce_characters(true);
+
+    let mut html = String::with_capacity(xhtml.len());
+    let mut open: Vec<Open> = vec![];
+    let mut warnings = BTreeSet::new();
+    let mut dropped_depth = 0;
+    let mut annotation_depth = 0;
+    let mut raw_text = false;
+
+    for event in config.create_reader(xhtml.as_bytes()) {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                debug!("{} isn't well-formed XML, reading it as HTML: {}", path, e);
+                return None;
+            }
+        };
+        if dropped_depth > 0 {
+            match event {
+                XmlEvent::StartElement { .. } => dropped_depth += 1,
+                XmlEvent::EndElement { .. } => dropped_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        match event {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                let ns = namespace(&name);
+                let local = name.local_name.as_str();
+                let in_switch = match open.last_mut() {
+                    Some(Open::Switch(picked)) => Some(picked),
+                    _ => None,
+                };
+                let action = if let Some(picked) = in_switch {
+                    let required = attributes
+                        .iter()
+                        .find(|a| a.name.local_name == "required-namespace")
+                        .map(|a| a.value.as_str());
+                    let usable = match (local, required) {
+                        ("case", Some(required)) => SUPPORTED_NAMESPACES.contains(&required),
+                        ("default", _) => true,
+                        _ => false,
+                    };
+                    if usable && !*picked {
+                        *picked = true;
+                        Open::Unwrapped
+                    } else {
+                        Open::Dropped
+                    }
+                } else if ns == EPUB_NS && local == "switch" {
+                    Open::Switch(false)
+                } else if ns == XHTML_NS || ns == MATHML_NS || ns == SVG_NS {
+                    if ns == MATHML_NS {
+                        if local == "annotation" || local == "annotation-xml" {
+                            annotation_depth += 1;
+                        } else if annotation_depth == 0 && UNRENDERED_MATHML.contains(&local) {
+                            warnings.insert(format!(
+                                "MathML <{}> isn't rendered by browsers",
+                                local
+                            ));
+                        }
+                    }
+                    let has_lang = attributes
+                        .iter()
+                        .any(|a| a.name.namespace.is_none() && a.name.local_name == "lang");
+                    html.push('<');
+                    html.push_str(local);
+                    for attribute in attributes.iter() {
+                        let attribute_name = match attribute.name.namespace.as_deref() {
+                            None => attribute.name.local_name.clone(),
+                            Some(XML_NS) if attribute.name.local_name == "lang" => {
+                                if has_lang {
+                                    continue;
+                                }
+                                "lang".to_string()
+                            }
+                            Some(XML_NS) => continue,
+                            Some(_) => qualified(&attribute.name),
+                        };
+                        html.push_str(&format!(
+                            " {}=\"{}\"",
+                            attribute_name,
+                            escape_attribute(&attribute.value)
+                        ));
+                    }
+                    html.push('>');
+                    let void = ns == XHTML_NS && VOID_ELEMENTS.contains(&local);
+                    raw_text = ns == XHTML_NS && (local == "script" || local == "style");
+                    Open::Written(if void { None } else { Some(local.to_string()) })
+                } else if ns == EPUB_NS && local == "trigger" {
+                    warnings.insert("epub:trigger isn't supported by browsers".to_string());
+                    Open::Dropped
+                } else {
+                    warnings.insert(format!(
+                        "<{}> in {} isn't rendered by browsers",
+                        qualified(&name),
+                        ns
+                    ));
+                    Open::Unwrapped
+                };
+                if let Open::Dropped = action {
+                    dropped_depth = 1;
+                } else {
+                    open.push(action);
+                }
+            }
+            XmlEvent::EndElement { name } => {
+                if namespace(&name) == MATHML_NS
+                    && (name.local_name == "annotation" || name.local_name == "annotation-xml")
+                {
+                    annotation_depth -= 1;
+                }
+                raw_text = false;
+                match open.pop() {
+                    Some(Open::Written(Some(tag))) => {
+                        html.push_str("</");
+                        html.push_str(&tag);
+                        html.push('>');
+                    }
+                    Some(Open::Switch(false)) => {
+                        warnings.insert("an epub:switch has no case browsers render".to_string());
+                    }
+                    _ => {}
+                }
+            }
+            XmlEvent::Characters(text) => {
+                if raw_text {
+                    html.push_str(&text);
+                } else {
+                    html.push_str(&escape_text(&text));
+                }
+            }
+            _ => {}
+        }
+    }
+    Some((html, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{convert, to_html};
+
+    fn chapter(body: &str) -> String {
+        format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>",
+                "<html xmlns=\"http://www.w3.org/1999/xhtml\" ",
+                "xmlns:epub=\"http://www.idpf.org/2007/ops\" ",
+                "xmlns:m=\"http://www.w3.org/1998/Math/MathML\">",
+                "<body>{}</body></html>"
+            ),
+            body
+        )
+    }
+
+    #[test]
+    fn closes_self_closing_elements() {
+        let html = to_html(&chapter("<p><a id=\"n1\"/>text<br/></p>"), "ch.xhtml").unwrap();
+        assert!(html.contains("<p><a id=\"n1\"></a>text<br></p>"));
+    }
+
+    #[test]
+    fn unprefixes_mathml() {
+        let body = "<p><m:math display=\"block\"><m:mi>x</m:mi></m:math></p>";
+        let html = to_html(&chapter(body), "ch.xhtml").unwrap();
+        assert!(html.contains("<math display=\"block\"><mi>x</mi></math>"));
+        assert!(!html.contains("m:"));
+    }
+
+    #[test]
+    fn picks_the_first_supported_switch_case() {
+        let body = concat!(
+            "<epub:switch id=\"s\">",
+            "<epub:case required-namespace=\"http://www.xml-cml.org/schema\">",
+            "<p>chemistry</p></epub:case>",
+            "<epub:case required-namespace=\"http://www.w3.org/1998/Math/MathML\">",
+            "<m:math><m:mn>1</m:mn></m:math></epub:case>",
+            "<epub:default><p>fallback</p></epub:default>",
+            "</epub:switch>"
+        );
+        let html = to_html(&chapter(body), "ch.xhtml").unwrap();
+        assert!(html.contains("<body><math><mn>1</mn></math></body>"));
+        assert!(!html.contains("chemistry"));
+        assert!(!html.contains("fallback"));
+    }
+
+    #[test]
+    fn warns_about_switches_without_a_usable_case() {
+        let body = concat!(
+            "<epub:switch id=\"s\">",
+            "<epub:case required-namespace=\"http://www.xml-cml.org/schema\">",
+            "<p>chemistry</p></epub:case>",
+            "</epub:switch>"
+        );
+        let (html, warnings) = convert(&chapter(body), "ch.xhtml").unwrap();
+        assert!(html.contains("<body></body>"));
+        assert!(warnings.contains("an epub:switch has no case browsers render"));
+    }
+
+    #[test]
+    fn knows_xhtml_entities() {
+        let html = to_html(&chapter("<p>a&nbsp;b&mdash;c &amp;</p>"), "ch.xhtml").unwrap();
+        assert!(html.contains("<p>a\u{a0}b\u{2014}c &amp;</p>"));
+    }
+
+    #[test]
+    fn leaves_broken_xml_to_the_html_parser() {
+        assert_eq!(to_html(&chapter("<p>unclosed"), "ch.xhtml"), None);
+    }
+}
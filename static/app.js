@@ -3,8 +3,17 @@ if ('serviceWorker' in navigator) {
   navigator.serviceWorker.register('sw.js');
 }
 
+// right-to-left books turn pages the other way
+var rtl = document.documentElement.dir === "rtl";
+
 function navigateWithArrows(ev) {
-  switch (ev.key) {
+  var key = ev.key;
+  if (rtl && key === "ArrowLeft") {
+    key = "ArrowRight";
+  } else if (rtl && key === "ArrowRight") {
+    key = "ArrowLeft";
+  }
+  switch (key) {
     case "ArrowLeft":
       var previousPage = document.querySelector("a.go-previous").getAttribute("href");
       window.location = previousPage;
@@ -31,4 +40,58 @@ if (location.hash) {
     para.classList.add("para-highlight");
     para.scrollIntoView({ behavior: "instant", block: "end", inline: "nearest" });
   }, 1000)
-}
\ No newline at end of file
+}
+
+// footnotes open in a popover next to their reference, the link itself
+// still points at the note for when this script doesn't run
+var openNote = null;
+
+function closeNote(returnFocus) {
+  if (!openNote) {
+    return;
+  }
+  var noteref = document.querySelector('a[data-note="' + openNote.id + '"]');
+  openNote.hidden = true;
+  noteref.setAttribute("aria-expanded", "false");
+  if (returnFocus) {
+    noteref.focus();
+  }
+  openNote = null;
+}
+
+function showNote(noteref) {
+  var note = document.getElementById(noteref.dataset.note);
+  var wasOpen = openNote === note;
+  closeNote(false);
+  if (wasOpen) {
+    return;
+  }
+  note.hidden = false;
+  var container = note.offsetParent || document.body;
+  var top = noteref.getBoundingClientRect().bottom - container.getBoundingClientRect().top;
+  note.style.top = (top + 8) + "px";
+  noteref.setAttribute("aria-expanded", "true");
+  openNote = note;
+  note.querySelector("a.note-popover-back").focus({ preventScroll: true });
+}
+
+document.addEventListener("click", function (ev) {
+  var noteref = ev.target.closest("a[data-note]");
+  if (noteref) {
+    ev.preventDefault();
+    showNote(noteref);
+  } else if (ev.target.closest("a.note-popover-back")) {
+    ev.preventDefault();
+    closeNote(true);
+  } else if (openNote && !openNote.contains(ev.target)) {
+    closeNote(false);
+  }
+});
+
+// Escape closes an open note instead of going to the table of contents
+document.addEventListener("keyup", function (ev) {
+  if (openNote && ev.key === "Escape") {
+    ev.stopImmediatePropagation();
+    closeNote(true);
+  }
+}, true);
@@ -0,0 +1,90 @@
+// Fixed-layout pages are drawn at the size the book declares and scaled
+// to fit the window. With the spread view on and the window wider than
+// tall, the other page of the spread is shown next to this one in a
+// frame, and the arrows go from spread to spread.
+var fixedLayout = document.querySelector("main.fixed-layout");
+var framed = window.self !== window.top;
+var partnerFrame = null;
+
+if (framed) {
+  document.documentElement.classList.add("fixed-framed");
+  // links in the other page of a spread open in the whole window
+  document.querySelectorAll("a[href]").forEach(function (link) {
+    link.target = "_top";
+  });
+}
+
+function spreadWanted() {
+  return !framed
+    && fixedLayout.dataset.partner
+    && localStorage.getItem("fixed-spread") === "on"
+    && window.innerWidth > window.innerHeight;
+}
+
+function updateNavigation(spread) {
+  var targets = {
+    "a.go-previous": fixedLayout.dataset.spreadPrevious,
+    "a.go-next": fixedLayout.dataset.spreadNext
+  };
+  Object.keys(targets).forEach(function (selector) {
+    document.querySelectorAll(selector).forEach(function (link) {
+      if (!link.dataset.page) {
+        link.dataset.page = link.getAttribute("href");
+      }
+      var target = spread ? targets[selector] : link.dataset.page;
+      link.setAttribute("href", target || link.dataset.page);
+    });
+  });
+}
+
+function fitPages() {
+  var spread = spreadWanted();
+  var page = fixedLayout.querySelector(".fixed-frame");
+  if (spread && !partnerFrame) {
+    partnerFrame = document.createElement("iframe");
+    partnerFrame.className = "fixed-partner";
+    partnerFrame.title = "Other page of the spread";
+    partnerFrame.src = fixedLayout.dataset.partner;
+    if (fixedLayout.dataset.side === "left") {
+      fixedLayout.appendChild(partnerFrame);
+    } else {
+      fixedLayout.insertBefore(partnerFrame, page);
+    }
+  } else if (!spread && partnerFrame) {
+    partnerFrame.remove();
+    partnerFrame = null;
+  }
+  updateNavigation(spread);
+
+  var width = Number(fixedLayout.dataset.width);
+  var height = Number(fixedLayout.dataset.height);
+  var top = fixedLayout.getBoundingClientRect().top + window.scrollY;
+  var available = Math.max(window.innerHeight - top, 100);
+  fixedLayout.style.height = available + "px";
+  var scale = Math.min(
+    fixedLayout.clientWidth / (spread ? 2 * width : width),
+    available / height
+  );
+  [page, partnerFrame].forEach(function (element) {
+    if (element) {
+      element.style.width = width * scale + "px";
+      element.style.height = height * scale + "px";
+    }
+  });
+  fixedLayout.querySelector(".fixed-page").style.transform = "scale(" + scale + ")";
+}
+
+if (fixedLayout) {
+  var toggle = document.querySelector(".fixed-spread-toggle");
+  if (toggle) {
+    toggle.setAttribute("aria-pressed", String(localStorage.getItem("fixed-spread") === "on"));
+    toggle.addEventListener("click", function () {
+      var on = localStorage.getItem("fixed-spread") !== "on";
+      localStorage.setItem("fixed-spread", on ? "on" : "off");
+      toggle.setAttribute("aria-pressed", String(on));
+      fitPages();
+    });
+  }
+  window.addEventListener("resize", fitPages);
+  fitPages();
+}
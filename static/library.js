@@ -0,0 +1,43 @@
+// Check that service workers are registered
+if ('serviceWorker' in navigator) {
+  navigator.serviceWorker.register('sw.js');
+}
+
+var search = document.getElementById("library-search");
+var language = document.getElementById("library-language");
+
+// case folding depends on the language, e.g. Turkish dotted i
+function fold(text, lang) {
+  try {
+    return text.toLocaleLowerCase(lang || undefined);
+  } catch (e) {
+    return text.toLowerCase();
+  }
+}
+
+function filterLibrary() {
+  var query = search ? search.value.trim() : "";
+  var selected = language ? language.value : "";
+
+  document.querySelectorAll("section.library-group").forEach(function (group) {
+    var groupMatches = !selected || group.dataset.language === selected;
+    var groupQuery = fold(query, group.lang).normalize("NFC");
+    var visible = 0;
+
+    group.querySelectorAll("li.library-book").forEach(function (book) {
+      var show = groupMatches && book.dataset.search.normalize("NFC").indexOf(groupQuery) !== -1;
+      book.hidden = !show;
+      if (show) {
+        visible++;
+      }
+    });
+    group.hidden = visible === 0;
+  });
+}
+
+if (search) {
+  search.addEventListener("input", filterLibrary);
+}
+if (language) {
+  language.addEventListener("change", filterLibrary);
+}
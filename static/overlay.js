@@ -0,0 +1,113 @@
+// Read-along narration from EPUB3 media overlays. The converter writes the
+// clips of each narrated chapter to overlays/<chapter>.json in reading
+// order: the element `id` is read from `begin` to `end` seconds of `audio`,
+// to the end of the file when `end` is null.
+var overlayPlayer = document.querySelector(".media-overlay-player");
+
+if (overlayPlayer) {
+  var overlayButton = overlayPlayer.querySelector(".media-overlay-toggle");
+  var activeClass = overlayPlayer.dataset.activeClass || "media-overlay-active";
+  var narration = new Audio();
+  var fragments = [];
+  var current = -1;
+
+  var highlight = function (index) {
+    var previous = fragments[current] && document.getElementById(fragments[current].id);
+    if (previous) {
+      previous.classList.remove(activeClass);
+    }
+    current = index;
+    var element = fragments[index] && document.getElementById(fragments[index].id);
+    if (element) {
+      element.classList.add(activeClass);
+      element.scrollIntoView({ behavior: "smooth", block: "nearest" });
+    }
+  };
+
+  var setPlaying = function (playing) {
+    overlayButton.setAttribute("aria-pressed", String(playing));
+    overlayButton.textContent = playing ? "Pause" : "Read aloud";
+  };
+
+  var stop = function () {
+    narration.pause();
+    highlight(-1);
+    setPlaying(false);
+  };
+
+  var seek = function (time) {
+    if (narration.readyState > 0) {
+      narration.currentTime = time;
+    } else {
+      narration.addEventListener("loadedmetadata", function () {
+        narration.currentTime = time;
+      }, { once: true });
+    }
+  };
+
+  var playFrom = function (index) {
+    var fragment = fragments[index];
+    if (!fragment) {
+      stop();
+      return;
+    }
+    highlight(index);
+    if (narration.getAttribute("src") !== fragment.audio) {
+      narration.src = fragment.audio;
+    }
+    seek(fragment.begin);
+    narration.play();
+    setPlaying(true);
+  };
+
+  narration.addEventListener("timeupdate", function () {
+    var fragment = fragments[current];
+    if (!fragment || fragment.end === null || narration.currentTime < fragment.end) {
+      return;
+    }
+    var next = fragments[current + 1];
+    // clips that follow on in the same file only move the highlight
+    if (next && next.audio === fragment.audio && Math.abs(next.begin - fragment.end) < 0.05) {
+      highlight(current + 1);
+    } else {
+      playFrom(current + 1);
+    }
+  });
+  narration.addEventListener("ended", function () {
+    playFrom(current + 1);
+  });
+
+  overlayButton.addEventListener("click", function () {
+    if (!narration.paused) {
+      narration.pause();
+      setPlaying(false);
+    } else if (current >= 0) {
+      narration.play();
+      setPlaying(true);
+    } else {
+      playFrom(0);
+    }
+  });
+
+  // clicking narrated text while it plays reads from there
+  document.addEventListener("click", function (ev) {
+    if (narration.paused || ev.target.closest("a, button")) {
+      return;
+    }
+    for (var element = ev.target; element; element = element.parentElement) {
+      var id = element.id;
+      var index = id ? fragments.findIndex(function (f) { return f.id === id; }) : -1;
+      if (index >= 0) {
+        playFrom(index);
+        return;
+      }
+    }
+  });
+
+  fetch(overlayPlayer.dataset.overlay)
+    .then(function (response) { return response.json(); })
+    .then(function (list) {
+      fragments = list;
+      overlayPlayer.hidden = fragments.length === 0;
+    });
+}
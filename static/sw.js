@@ -1,7 +1,11 @@
 importScripts('https://storage.googleapis.com/workbox-cdn/releases/3.4.1/workbox-sw.js');
+// narration audio and timings, listed by the converter
+importScripts('precache-manifest.js');
 
 if (workbox) {
   console.log('workbox loaded');
+  workbox.precaching.precacheAndRoute(self.__precacheManifest || []);
+
   // cache js
   workbox.routing.registerRoute(
     new RegExp('.*\.js'),
@@ -10,7 +14,7 @@ if (workbox) {
 
   // cache images
   workbox.routing.registerRoute(
-    /\.(?:png|gif|jpg|jpeg|svg)$/,
+    /\.(?:png|gif|jpg|jpeg|svg|webp)$/,
     workbox.strategies.staleWhileRevalidate(),
   );
 
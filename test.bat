@@ -1,3 +1,3 @@
 @echo off
 set RUST_BACKTRACE=1
-call .\target\release\epub2pwa -b all_books_local.json
\ No newline at end of file
+call .\target\release\epub2pwa batch all_books_local.json
\ No newline at end of file